@@ -0,0 +1,70 @@
+//! Selection between GStreamer's `v4l2src` and the native capture sources
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "libcamera")]
+use super::libcamera::LibcameraCapture;
+use super::{
+    file::FileSource, image_sequence::ImageSequenceSource, source::CaptureSource,
+    test_pattern::TestPatternSource, v4l2::V4l2Capture,
+};
+use crate::CaptureConfig;
+
+/// Where frames are captured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaptureBackend {
+    /// `v4l2src` inside the GStreamer pipeline
+    #[default]
+    GStreamer,
+    /// Native V4L2 streaming, fed to the display through `appsrc`
+    V4l2,
+    /// libcamera, fed to the display through `appsrc` (needs the `libcamera` feature)
+    Libcamera,
+    /// Video or MJPEG file at `capture.device`, looped
+    File,
+    /// Directory of JPEG/PNG images at `capture.device`, looped in name order
+    ImageSequence,
+    /// Moving colour bars with a frame counter; needs no hardware
+    TestPattern,
+}
+
+impl CaptureBackend {
+    /// Whether this build can open the backend
+    pub fn is_available(&self) -> bool {
+        cfg!(feature = "libcamera") || *self != CaptureBackend::Libcamera
+    }
+
+    /// Whether `capture.device` names a V4L2 node
+    pub fn uses_v4l2_device(&self) -> bool {
+        matches!(self, CaptureBackend::GStreamer | CaptureBackend::V4l2)
+    }
+
+    /// Whether `capture.device` names a file or directory
+    pub fn uses_path(&self) -> bool {
+        matches!(self, CaptureBackend::File | CaptureBackend::ImageSequence)
+    }
+}
+
+/// Open the native source selected by `config.backend`
+pub fn open_source(config: &CaptureConfig) -> Result<Box<dyn CaptureSource>> {
+    Ok(match config.backend {
+        CaptureBackend::V4l2 => Box::new(V4l2Capture::open(config)?),
+        #[cfg(feature = "libcamera")]
+        CaptureBackend::Libcamera => Box::new(LibcameraCapture::open(config)?),
+        #[cfg(not(feature = "libcamera"))]
+        CaptureBackend::Libcamera => {
+            return Err(eyre!(
+                "The libcamera backend requires building with `--features libcamera`"
+            ))
+        }
+        CaptureBackend::File => Box::new(FileSource::open(config)?),
+        CaptureBackend::ImageSequence => Box::new(ImageSequenceSource::open(config)?),
+        CaptureBackend::TestPattern => Box::new(TestPatternSource::new(config)),
+        CaptureBackend::GStreamer => {
+            return Err(eyre!(
+                "The GStreamer backend captures inside the pipeline, not natively"
+            ))
+        }
+    })
+}
@@ -0,0 +1,154 @@
+//! Encode packed RGB images into the capture pixel formats
+
+use bytes::Bytes;
+use color_eyre::{eyre::eyre, Result};
+use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+
+use super::frame::PixelFormat;
+
+/// JPEG quality used when a source has to produce MJPEG itself
+const JPEG_QUALITY: u8 = 85;
+
+/// Encode a packed RGB24 image as `format`, returning the data and its stride
+///
+/// YUV output uses BT.601 limited range; chroma is taken from the top-left
+/// pixel of each subsampled block.
+pub(super) fn encode_rgb(
+    rgb: &[u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+) -> Result<(Bytes, u32)> {
+    let (w, h) = (width as usize, height as usize);
+    if rgb.len() != w * h * 3 {
+        return Err(eyre!(
+            "RGB buffer is {} bytes, expected {}x{}x3",
+            rgb.len(),
+            width,
+            height
+        ));
+    }
+
+    let encoded = match format {
+        PixelFormat::Rgb24 => (Bytes::copy_from_slice(rgb), width * 3),
+        PixelFormat::Bgr24 => {
+            let bgr: Vec<u8> = rgb
+                .chunks_exact(3)
+                .flat_map(|px| [px[2], px[1], px[0]])
+                .collect();
+            (Bytes::from(bgr), width * 3)
+        }
+        PixelFormat::Yuyv4 => {
+            let mut yuyv = Vec::with_capacity(w * h * 2);
+            for pair in rgb.chunks_exact(6) {
+                let (y0, u, v) = rgb_to_yuv(&pair[..3]);
+                let (y1, _, _) = rgb_to_yuv(&pair[3..]);
+                yuyv.extend_from_slice(&[y0, u, y1, v]);
+            }
+            (Bytes::from(yuyv), width * 2)
+        }
+        PixelFormat::Nv12 => {
+            let mut nv12 = Vec::with_capacity(w * h * 3 / 2);
+            nv12.extend(rgb.chunks_exact(3).map(|px| rgb_to_yuv(px).0));
+            for row in (0..h).step_by(2) {
+                for col in (0..w).step_by(2) {
+                    let offset = (row * w + col) * 3;
+                    let (_, u, v) = rgb_to_yuv(&rgb[offset..offset + 3]);
+                    nv12.extend_from_slice(&[u, v]);
+                }
+            }
+            (Bytes::from(nv12), width)
+        }
+        PixelFormat::Mjpeg => {
+            let mut jpeg = Vec::new();
+            JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode(
+                rgb,
+                width,
+                height,
+                ExtendedColorType::Rgb8,
+            )?;
+            (Bytes::from(jpeg), 0)
+        }
+        PixelFormat::H264 | PixelFormat::H265 => {
+            return Err(eyre!("{:?} frames cannot be encoded one at a time", format))
+        }
+    };
+
+    Ok(encoded)
+}
+
+fn rgb_to_yuv(px: &[u8]) -> (u8, u8, u8) {
+    let (r, g, b) = (px[0] as i32, px[1] as i32, px[2] as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const BLACK: [u8; 3] = [0, 0, 0];
+    const RED: [u8; 3] = [255, 0, 0];
+
+    #[test]
+    fn rejects_a_buffer_of_the_wrong_size() {
+        assert!(encode_rgb(&[0; 11], 2, 2, PixelFormat::Rgb24).is_err());
+    }
+
+    #[test]
+    fn packed_rgb_keeps_or_swaps_the_channels() {
+        let rgb = [RED, WHITE].concat();
+
+        let (data, stride) = encode_rgb(&rgb, 2, 1, PixelFormat::Rgb24).unwrap();
+        assert_eq!((&data[..], stride), (&rgb[..], 6));
+
+        let (data, stride) = encode_rgb(&rgb, 2, 1, PixelFormat::Bgr24).unwrap();
+        assert_eq!((&data[..], stride), (&[0, 0, 255, 255, 255, 255][..], 6));
+    }
+
+    #[test]
+    fn yuv_uses_limited_range() {
+        assert_eq!(rgb_to_yuv(&WHITE), (235, 128, 128));
+        assert_eq!(rgb_to_yuv(&BLACK), (16, 128, 128));
+    }
+
+    #[test]
+    fn yuyv_shares_chroma_between_pixel_pairs() {
+        let rgb = [WHITE, BLACK, BLACK, WHITE].concat();
+        let (data, stride) = encode_rgb(&rgb, 4, 1, PixelFormat::Yuyv4).unwrap();
+        assert_eq!(stride, 8);
+        assert_eq!(&data[..], &[235, 128, 16, 128, 16, 128, 235, 128]);
+    }
+
+    #[test]
+    fn nv12_has_a_luma_plane_then_interleaved_chroma() {
+        let rgb = [RED, WHITE, BLACK, WHITE].concat();
+        let (data, stride) = encode_rgb(&rgb, 2, 2, PixelFormat::Nv12).unwrap();
+        let (_, u, v) = rgb_to_yuv(&RED);
+
+        assert_eq!(stride, 2);
+        assert_eq!(data.len(), 6);
+        assert_eq!(&data[1..4], &[235, 16, 235]);
+        // Chroma comes from the top-left pixel of the block
+        assert_eq!(&data[4..], &[u, v]);
+    }
+
+    #[test]
+    fn mjpeg_is_a_baseline_jpeg_without_stride() {
+        let rgb = [RED; 16].concat();
+        let (data, stride) = encode_rgb(&rgb, 4, 4, PixelFormat::Mjpeg).unwrap();
+        assert_eq!(stride, 0);
+        assert_eq!(&data[..2], &[0xff, 0xd8]);
+        assert_eq!(&data[data.len() - 2..], &[0xff, 0xd9]);
+    }
+
+    #[test]
+    fn inter_coded_formats_are_refused() {
+        let rgb = [BLACK; 4].concat();
+        assert!(encode_rgb(&rgb, 2, 2, PixelFormat::H264).is_err());
+        assert!(encode_rgb(&rgb, 2, 2, PixelFormat::H265).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! V4L2 capture buffers exported as DMA-BUF file descriptors (VIDIOC_EXPBUF)
+
+use std::{
+    io, mem,
+    os::fd::{FromRawFd, OwnedFd},
+    os::raw::{c_int, c_void},
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use v4l::{
+    buffer::{Metadata, Type},
+    device::Handle,
+    memory::Memory,
+    v4l2,
+    v4l_sys::{v4l2_buffer, v4l2_exportbuffer, v4l2_requestbuffers},
+    Device,
+};
+
+use super::frame::DmaBuf;
+
+/// Hands a dequeued buffer back to its stream when the last frame using it drops
+#[derive(Debug)]
+pub(crate) struct BufferLease {
+    index: u32,
+    released: mpsc::Sender<u32>,
+}
+
+impl Drop for BufferLease {
+    fn drop(&mut self) {
+        // A stopped stream no longer wants its buffers back
+        let _ = self.released.send(self.index);
+    }
+}
+
+/// Driver-allocated buffers streamed by index and handed out as DMA-BUF fds
+///
+/// A dequeued buffer stays with the consumer until every clone of its
+/// [`DmaBuf`] is dropped, including any GStreamer memory wrapping it; the next
+/// call to [`DmaBufStream::next`] then gives it back to the driver.
+pub(super) struct DmaBufStream {
+    handle: Arc<Handle>,
+    fds: Vec<Arc<OwnedFd>>,
+    /// Buffers currently owned by the driver
+    queued: usize,
+    release: mpsc::Sender<u32>,
+    released: mpsc::Receiver<u32>,
+    active: bool,
+    timeout: Duration,
+}
+
+impl DmaBufStream {
+    /// Allocate `buffer_count` mmap buffers and export each one
+    ///
+    /// Fails if the driver cannot export its buffers, leaving the device free
+    /// for a plain mmap stream.
+    pub(super) fn with_buffers(
+        device: &Device,
+        buffer_count: u32,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let handle = device.handle();
+        let count = request_buffers(&handle, buffer_count)?;
+
+        let mut fds = Vec::with_capacity(count as usize);
+        for index in 0..count {
+            let mut export = v4l2_exportbuffer {
+                type_: Type::VideoCapture as u32,
+                index,
+                flags: (libc::O_RDONLY | libc::O_CLOEXEC) as u32,
+                // SAFETY: plain C struct for which all-zero is a valid value
+                ..unsafe { mem::zeroed() }
+            };
+            // SAFETY: `export` is a v4l2_exportbuffer, as VIDIOC_EXPBUF expects
+            let exported = unsafe { ioctl(&handle, v4l2::vidioc::VIDIOC_EXPBUF, &mut export) };
+            if let Err(e) = exported {
+                drop(fds);
+                let _ = request_buffers(&handle, 0);
+                return Err(e);
+            }
+            // SAFETY: VIDIOC_EXPBUF succeeded, so `export.fd` is a new fd owned by us
+            fds.push(Arc::new(unsafe { OwnedFd::from_raw_fd(export.fd) }));
+        }
+
+        let (release, released) = mpsc::channel();
+        Ok(Self {
+            handle,
+            fds,
+            queued: 0,
+            release,
+            released,
+            active: false,
+            timeout,
+        })
+    }
+
+    /// Return released buffers to the driver and wait for the next frame
+    pub(super) fn next(&mut self) -> io::Result<(DmaBuf, Metadata)> {
+        if !self.active {
+            for index in 0..self.fds.len() as u32 {
+                self.queue(index)?;
+            }
+            let mut buf_type = Type::VideoCapture as c_int;
+            // SAFETY: VIDIOC_STREAMON takes the buffer type as a C int
+            unsafe { ioctl(&self.handle, v4l2::vidioc::VIDIOC_STREAMON, &mut buf_type)? };
+            self.active = true;
+        }
+
+        while let Ok(index) = self.released.try_recv() {
+            self.queue(index)?;
+        }
+        // Every buffer is still held downstream; the driver has nowhere to write
+        if self.queued == 0 {
+            let index = self.released.recv_timeout(self.timeout).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "every DMA-BUF is still held downstream",
+                )
+            })?;
+            self.queue(index)?;
+        }
+
+        let timeout_ms = self.timeout.as_millis().min(i32::MAX as u128) as i32;
+        if self.handle.poll(libc::POLLIN, timeout_ms)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "VIDIOC_DQBUF"));
+        }
+
+        let mut buffer = buffer_desc(0);
+        // SAFETY: `buffer` is a v4l2_buffer, as VIDIOC_DQBUF expects
+        unsafe { ioctl(&self.handle, v4l2::vidioc::VIDIOC_DQBUF, &mut buffer)? };
+        self.queued -= 1;
+
+        let dmabuf = DmaBuf {
+            fd: self.fds[buffer.index as usize].clone(),
+            index: buffer.index,
+            len: buffer.bytesused,
+            _lease: Arc::new(BufferLease {
+                index: buffer.index,
+                released: self.release.clone(),
+            }),
+        };
+        let meta = Metadata {
+            bytesused: buffer.bytesused,
+            flags: buffer.flags.into(),
+            field: buffer.field,
+            timestamp: buffer.timestamp.into(),
+            sequence: buffer.sequence,
+        };
+        Ok((dmabuf, meta))
+    }
+
+    fn queue(&mut self, index: u32) -> io::Result<()> {
+        let mut buffer = buffer_desc(index);
+        // SAFETY: `buffer` is a v4l2_buffer, as VIDIOC_QBUF expects
+        unsafe { ioctl(&self.handle, v4l2::vidioc::VIDIOC_QBUF, &mut buffer)? };
+        self.queued += 1;
+        Ok(())
+    }
+}
+
+impl Drop for DmaBufStream {
+    fn drop(&mut self) {
+        if self.active {
+            let mut buf_type = Type::VideoCapture as c_int;
+            // SAFETY: VIDIOC_STREAMOFF takes the buffer type as a C int
+            let _ = unsafe { ioctl(&self.handle, v4l2::vidioc::VIDIOC_STREAMOFF, &mut buf_type) };
+        }
+        // Consumers may still hold fds; the kernel keeps those buffers alive
+        self.fds.clear();
+        let _ = request_buffers(&self.handle, 0);
+    }
+}
+
+fn request_buffers(handle: &Handle, count: u32) -> io::Result<u32> {
+    let mut request = v4l2_requestbuffers {
+        count,
+        type_: Type::VideoCapture as u32,
+        memory: Memory::Mmap as u32,
+        // SAFETY: plain C struct for which all-zero is a valid value
+        ..unsafe { mem::zeroed() }
+    };
+    // SAFETY: `request` is a v4l2_requestbuffers, as VIDIOC_REQBUFS expects
+    unsafe { ioctl(handle, v4l2::vidioc::VIDIOC_REQBUFS, &mut request)? };
+    Ok(request.count)
+}
+
+fn buffer_desc(index: u32) -> v4l2_buffer {
+    v4l2_buffer {
+        index,
+        type_: Type::VideoCapture as u32,
+        memory: Memory::Mmap as u32,
+        // SAFETY: plain C struct for which all-zero is a valid value
+        ..unsafe { mem::zeroed() }
+    }
+}
+
+/// # Safety
+///
+/// `arg` must be the argument type the kernel expects for `request`.
+unsafe fn ioctl<T>(
+    handle: &Handle,
+    request: v4l2::vidioc::_IOC_TYPE,
+    arg: &mut T,
+) -> io::Result<()> {
+    // SAFETY: the handle's fd is open, and the caller matched `arg` to `request`
+    unsafe { v4l2::ioctl(handle.fd(), request, arg as *mut T as *mut c_void) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_returns_when_the_last_holder_drops() {
+        let (release, released) = mpsc::channel();
+        let lease = Arc::new(BufferLease {
+            index: 3,
+            released: release,
+        });
+        let held_downstream = lease.clone();
+
+        drop(lease);
+        assert!(released.try_recv().is_err());
+        drop(held_downstream);
+        assert_eq!(released.try_recv(), Ok(3));
+    }
+}
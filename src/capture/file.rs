@@ -0,0 +1,190 @@
+//! A video or MJPEG file played back in a loop through a GStreamer decoder
+
+use std::{path::Path, sync::Arc, time::Instant};
+
+use bytes::Bytes;
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use gstreamer_video as gst_video;
+use tracing::{debug, info};
+
+use super::{
+    frame::{Frame, FrameMetadata, PixelFormat},
+    source::CaptureSource,
+};
+use crate::{display::PipelineBuilder, CaptureConfig};
+
+/// Name of the appsink delivering decoded frames
+const FRAME_SINK_NAME: &str = "framesink";
+
+/// How long to wait for a decoded frame before checking the bus
+const PULL_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(2);
+
+/// Decodes a file at its own pace and restarts it on end-of-stream
+///
+/// Frames are converted, scaled and rate-adapted to the capture settings, so
+/// any container GStreamer can decode works. Raw `.mjpeg`/`.mjpg` streams of
+/// concatenated JPEGs are parsed directly.
+pub struct FileSource {
+    pipeline: gst::Pipeline,
+    appsink: gst_app::AppSink,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    sequence: u64,
+}
+
+impl FileSource {
+    pub fn open(config: &CaptureConfig) -> Result<Self> {
+        gst::init().map_err(|e| eyre!("Failed to initialize GStreamer: {}", e))?;
+
+        let path = Path::new(&config.device.path);
+        if !path.is_file() {
+            return Err(eyre!("Capture file {} does not exist", path.display()));
+        }
+        let is_mjpeg = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("mjpeg") || ext.eq_ignore_ascii_case("mjpg")
+            });
+        // `location` is set as a property, so any path works, quotes included
+        let mut builder = PipelineBuilder::new("filesource");
+        builder
+            .stage("filesrc", "filesrc")?
+            .set_property("location", &config.device.path);
+        if is_mjpeg {
+            builder.stage("jpegparse", "parse")?;
+            builder.stage("jpegdec", "decode")?;
+        } else {
+            builder.stage("decodebin", "decode")?;
+        }
+        builder.stage("videoconvert", "convert")?;
+        builder.stage("videoscale", "scale")?;
+        builder.stage("videorate", "rate")?;
+
+        let (numerator, denominator) = config.framerate();
+        let raw = gst::Caps::builder("video/x-raw")
+            .field("width", config.width as i32)
+            .field("height", config.height as i32)
+            .field(
+                "framerate",
+                gst::Fraction::new(numerator as i32, denominator as i32),
+            );
+        match (config.format.gst_format(), config.format) {
+            (Some(format), _) => {
+                builder.caps("rawcaps", &raw.field("format", format).build())?;
+            }
+            (None, PixelFormat::Mjpeg) => {
+                builder.caps("rawcaps", &raw.build())?;
+                builder.stage("jpegenc", "encode")?;
+                builder.caps("jpegcaps", &gst::Caps::new_empty_simple("image/jpeg"))?;
+            }
+            (None, format) => return Err(eyre!("File source cannot produce {:?}", format)),
+        }
+
+        let sink = builder.stage("appsink", FRAME_SINK_NAME)?;
+        sink.set_property("sync", true);
+        sink.set_property("max-buffers", 2u32);
+        let appsink = sink
+            .downcast::<gst_app::AppSink>()
+            .map_err(|_| eyre!("{} is not an appsink", FRAME_SINK_NAME))?;
+        let (pipeline, _) = builder.finish();
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|_| eyre!("Failed to start playback of {}", path.display()))?;
+        info!("Playing {} in a loop", path.display());
+
+        Ok(Self {
+            pipeline,
+            appsink,
+            width: config.width,
+            height: config.height,
+            format: config.format,
+            sequence: 0,
+        })
+    }
+
+    /// Restart playback from the first frame
+    fn rewind(&self) -> Result<()> {
+        debug!("End of file; looping");
+        self.pipeline
+            .seek_simple(
+                gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                gst::ClockTime::ZERO,
+            )
+            .map_err(|e| eyre!("Failed to loop capture file: {}", e))
+    }
+
+    /// The first error posted by the decoder, if any
+    fn bus_error(&self) -> Option<color_eyre::Report> {
+        let message = self
+            .pipeline
+            .bus()?
+            .pop_filtered(&[gst::MessageType::Error])?;
+        match message.view() {
+            gst::MessageView::Error(err) => Some(eyre!(
+                "File playback error: {} ({})",
+                err.error(),
+                err.debug().unwrap_or_default()
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl CaptureSource for FileSource {
+    fn next_frame(&mut self) -> Result<Frame> {
+        let sample = loop {
+            if let Some(sample) = self.appsink.try_pull_sample(PULL_TIMEOUT) {
+                break sample;
+            }
+            if let Some(err) = self.bus_error() {
+                return Err(err);
+            }
+            if !self.appsink.is_eos() {
+                return Err(eyre!("Timed out waiting for a decoded frame"));
+            }
+            self.rewind()?;
+        };
+        let timestamp = Instant::now();
+
+        let buffer = sample
+            .buffer()
+            .ok_or_else(|| eyre!("Decoded sample has no buffer"))?;
+        let map = buffer
+            .map_readable()
+            .map_err(|e| eyre!("Failed to map decoded frame: {}", e))?;
+        let stride = match (self.format.gst_format(), sample.caps()) {
+            (Some(_), Some(caps)) => gst_video::VideoInfo::from_caps(caps)
+                .map(|info| info.stride()[0] as u32)
+                .unwrap_or(0),
+            _ => 0,
+        };
+
+        let frame = Frame {
+            data: Bytes::copy_from_slice(map.as_slice()),
+            meta: Arc::new(FrameMetadata {
+                sequence: self.sequence,
+                width: self.width,
+                height: self.height,
+                stride,
+                format: self.format,
+                device_timestamp: None,
+            }),
+            timestamp,
+            dmabuf: None,
+        };
+
+        self.sequence += 1;
+        Ok(frame)
+    }
+}
+
+impl Drop for FileSource {
+    fn drop(&mut self) {
+        self.pipeline.set_state(gst::State::Null).ok();
+    }
+}
@@ -1,8 +1,11 @@
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use super::dmabuf::BufferLease;
+
 /// Frame data with zero-copy semantics
 #[derive(Clone)]
 pub struct Frame {
@@ -14,6 +17,29 @@ pub struct Frame {
 
     /// Capture timestamp for latency tracking
     pub timestamp: Instant,
+
+    /// Driver buffer exported as DMA-BUF; `data` is empty when this is set
+    pub dmabuf: Option<DmaBuf>,
+}
+
+/// A capture buffer shared as a DMA-BUF file descriptor for zero-copy import
+///
+/// The driver gets the buffer back once every clone has been dropped.
+#[derive(Debug, Clone)]
+pub struct DmaBuf {
+    pub fd: Arc<OwnedFd>,
+    /// Driver buffer index
+    pub index: u32,
+    /// Bytes of frame data in the buffer
+    pub len: u32,
+    /// Returns the buffer to the driver when the last clone drops
+    pub(crate) _lease: Arc<BufferLease>,
+}
+
+impl AsFd for DmaBuf {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
 }
 
 /// Frame metadata
@@ -35,4 +61,61 @@ pub enum PixelFormat {
     Yuyv4,
     Mjpeg,
     Nv12,
+    H264,
+    H265,
+}
+
+impl PixelFormat {
+    /// V4L2 FourCC code for this format
+    pub fn fourcc(&self) -> [u8; 4] {
+        match self {
+            PixelFormat::Rgb24 => *b"RGB3",
+            PixelFormat::Bgr24 => *b"BGR3",
+            PixelFormat::Yuyv4 => *b"YUYV",
+            PixelFormat::Mjpeg => *b"MJPG",
+            PixelFormat::Nv12 => *b"NV12",
+            PixelFormat::H264 => *b"H264",
+            PixelFormat::H265 => *b"HEVC",
+        }
+    }
+
+    /// GStreamer `video/x-raw` format name, or `None` for compressed formats
+    pub fn gst_format(&self) -> Option<&'static str> {
+        match self {
+            PixelFormat::Rgb24 => Some("RGB"),
+            PixelFormat::Bgr24 => Some("BGR"),
+            PixelFormat::Yuyv4 => Some("YUY2"),
+            PixelFormat::Nv12 => Some("NV12"),
+            PixelFormat::Mjpeg | PixelFormat::H264 | PixelFormat::H265 => None,
+        }
+    }
+
+    /// GStreamer media type of this format's caps
+    pub fn gst_media_type(&self) -> &'static str {
+        match self {
+            PixelFormat::Mjpeg => "image/jpeg",
+            PixelFormat::H264 => "video/x-h264",
+            PixelFormat::H265 => "video/x-h265",
+            _ => "video/x-raw",
+        }
+    }
+
+    /// Frames reference earlier frames, so they cannot be produced one image at a time
+    pub fn is_inter_coded(&self) -> bool {
+        matches!(self, PixelFormat::H264 | PixelFormat::H265)
+    }
+
+    /// Map a V4L2 FourCC code to a supported format
+    pub fn from_fourcc(fourcc: &[u8; 4]) -> Option<Self> {
+        match fourcc {
+            b"RGB3" => Some(PixelFormat::Rgb24),
+            b"BGR3" => Some(PixelFormat::Bgr24),
+            b"YUYV" => Some(PixelFormat::Yuyv4),
+            b"MJPG" | b"JPEG" => Some(PixelFormat::Mjpeg),
+            b"NV12" => Some(PixelFormat::Nv12),
+            b"H264" => Some(PixelFormat::H264),
+            b"HEVC" => Some(PixelFormat::H265),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,104 @@
+//! A directory of still images played back as a looping video
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use image::imageops::{self, FilterType};
+use tracing::info;
+
+use super::{
+    convert::encode_rgb,
+    frame::{Frame, FrameMetadata, PixelFormat},
+    source::{CaptureSource, FramePacer},
+};
+use crate::CaptureConfig;
+
+/// File extensions picked up from the directory
+const IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Plays the images of a directory in file-name order, scaled to the capture size
+pub struct ImageSequenceSource {
+    images: Vec<PathBuf>,
+    next: usize,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    pacer: FramePacer,
+    sequence: u64,
+}
+
+impl ImageSequenceSource {
+    pub fn open(config: &CaptureConfig) -> Result<Self> {
+        let dir = Path::new(&config.device.path);
+        let mut images: Vec<PathBuf> = std::fs::read_dir(dir)
+            .map_err(|e| eyre!("Failed to read image directory {}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| {
+                        IMAGE_EXTENSIONS
+                            .iter()
+                            .any(|known| ext.eq_ignore_ascii_case(known))
+                    })
+            })
+            .collect();
+        if images.is_empty() {
+            return Err(eyre!("No JPEG or PNG images in {}", dir.display()));
+        }
+        images.sort();
+        info!("Playing {} images from {}", images.len(), dir.display());
+
+        Ok(Self {
+            images,
+            next: 0,
+            width: config.width,
+            height: config.height,
+            format: config.format,
+            pacer: FramePacer::new(config.fps),
+            sequence: 0,
+        })
+    }
+
+    fn load(&self, path: &Path) -> Result<Vec<u8>> {
+        let image = image::open(path)
+            .map_err(|e| eyre!("Failed to load {}: {}", path.display(), e))?
+            .to_rgb8();
+        let image = if image.dimensions() == (self.width, self.height) {
+            image
+        } else {
+            imageops::resize(&image, self.width, self.height, FilterType::Triangle)
+        };
+        Ok(image.into_raw())
+    }
+}
+
+impl CaptureSource for ImageSequenceSource {
+    fn next_frame(&mut self) -> Result<Frame> {
+        let rgb = self.load(&self.images[self.next])?;
+        self.next = (self.next + 1) % self.images.len();
+
+        let (data, stride) = encode_rgb(&rgb, self.width, self.height, self.format)?;
+        self.pacer.wait();
+        let frame = Frame {
+            data,
+            meta: Arc::new(FrameMetadata {
+                sequence: self.sequence,
+                width: self.width,
+                height: self.height,
+                stride,
+                format: self.format,
+                device_timestamp: None,
+            }),
+            timestamp: Instant::now(),
+            dmabuf: None,
+        };
+
+        self.sequence += 1;
+        Ok(frame)
+    }
+}
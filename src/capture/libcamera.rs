@@ -0,0 +1,340 @@
+//! libcamera capture for CSI sensors and other cameras behind the modern camera stack
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, TrySendError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use color_eyre::{eyre::eyre, Result};
+use libcamera::{
+    camera::{Camera, CameraConfigurationStatus},
+    camera_manager::CameraManager,
+    control::ControlList,
+    controls,
+    framebuffer::AsFrameBuffer,
+    framebuffer_allocator::{FrameBuffer, FrameBufferAllocator},
+    framebuffer_map::MemoryMappedFrameBuffer,
+    geometry::Size,
+    pixel_format::PixelFormat as CameraFormat,
+    properties,
+    request::{RequestStatus, ReuseFlag},
+    stream::StreamRole,
+};
+use tracing::{debug, info, warn};
+
+use super::{
+    frame::{Frame, FrameMetadata, PixelFormat},
+    sequence::SequenceTracker,
+    source::CaptureSource,
+};
+use crate::CaptureConfig;
+
+/// How long to wait for a completed request before giving up
+const FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Capture from a libcamera camera
+///
+/// libcamera objects borrow their camera manager, so the camera lives on a
+/// worker thread that hands finished frames over a channel.
+pub struct LibcameraCapture {
+    frames: mpsc::Receiver<Result<Frame>>,
+    stop: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl LibcameraCapture {
+    /// Open the camera selected by `device.path` and start streaming
+    ///
+    /// The selector is matched against the libcamera camera id and model; an
+    /// empty selector picks the first camera.
+    pub fn open(config: &CaptureConfig) -> Result<Self> {
+        let (frames_tx, frames) = mpsc::sync_channel(config.buffer_count.max(1) as usize);
+        let (ready_tx, ready) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let worker = thread::Builder::new().name("libcamera".into()).spawn({
+            let config = config.clone();
+            let stop = stop.clone();
+            let dropped = dropped.clone();
+            move || {
+                // Errors share the frames channel, so none is lost between the
+                // ready signal and the first frame
+                if let Err(e) = stream_frames(&config, &ready_tx, &frames_tx, &stop, &dropped) {
+                    let _ = frames_tx.send(Err(e));
+                }
+            }
+        })?;
+
+        let mut capture = Self {
+            frames,
+            stop,
+            dropped,
+            worker: Some(worker),
+        };
+        if ready.recv().is_ok() {
+            return Ok(capture);
+        }
+        // The worker hung up without starting; its error is on the frames channel
+        let error = capture.frames.recv().ok().and_then(Result::err);
+        capture.stop();
+        Err(error.unwrap_or_else(|| eyre!("libcamera worker exited during start-up")))
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl CaptureSource for LibcameraCapture {
+    fn next_frame(&mut self) -> Result<Frame> {
+        match self.frames.recv_timeout(FRAME_TIMEOUT) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => Err(eyre!("Timed out waiting for a libcamera frame")),
+            Err(RecvTimeoutError::Disconnected) => Err(eyre!("libcamera worker stopped")),
+        }
+    }
+
+    /// Frames the camera skipped or the consumer was too slow to take
+    fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for LibcameraCapture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Configure the camera, then capture until `stop` is set
+fn stream_frames(
+    config: &CaptureConfig,
+    ready: &mpsc::Sender<()>,
+    frames: &mpsc::SyncSender<Result<Frame>>,
+    stop: &AtomicBool,
+    dropped: &AtomicU64,
+) -> Result<()> {
+    let manager = CameraManager::new().map_err(|e| eyre!("Failed to start libcamera: {}", e))?;
+    let cameras = manager.cameras();
+    let selector = config.device.path.as_str();
+    let camera = (0..cameras.len())
+        .filter_map(|index| cameras.get(index))
+        .find(|camera| camera_matches(camera, selector))
+        .ok_or_else(|| eyre!("No libcamera camera matches `{}`", selector))?;
+    let mut camera = camera
+        .acquire()
+        .map_err(|e| eyre!("Failed to acquire camera {}: {}", camera.id(), e))?;
+
+    let format = camera_format(config.format);
+    let mut configuration = camera
+        .generate_configuration(&[StreamRole::VideoRecording])
+        .ok_or_else(|| eyre!("Camera {} has no video stream", camera.id()))?;
+    {
+        let mut stream_config = configuration
+            .get_mut(0)
+            .ok_or_else(|| eyre!("Camera {} has no video stream", camera.id()))?;
+        stream_config.set_pixel_format(format);
+        stream_config.set_size(Size {
+            width: config.width,
+            height: config.height,
+        });
+        stream_config.set_buffer_count(config.buffer_count);
+    }
+    match configuration.validate() {
+        CameraConfigurationStatus::Invalid => {
+            return Err(eyre!(
+                "Camera {} rejected the stream configuration",
+                camera.id()
+            ))
+        }
+        CameraConfigurationStatus::Adjusted => {
+            debug!("Adjusted configuration: {:?}", configuration)
+        }
+        CameraConfigurationStatus::Valid => {}
+    }
+
+    let (size, stride) = {
+        let stream_config = configuration.get(0).unwrap();
+        if stream_config.get_pixel_format() != format {
+            return Err(eyre!(
+                "Camera {} does not support {:?} (offers {:?})",
+                camera.id(),
+                config.format,
+                stream_config.get_pixel_format()
+            ));
+        }
+        (stream_config.get_size(), stream_config.get_stride())
+    };
+    if (size.width, size.height) != (config.width, config.height) {
+        warn!(
+            "Camera {} adjusted {}x{} to {}x{}",
+            camera.id(),
+            config.width,
+            config.height,
+            size.width,
+            size.height
+        );
+    }
+
+    camera
+        .configure(&mut configuration)
+        .map_err(|e| eyre!("Failed to configure camera {}: {}", camera.id(), e))?;
+    let stream = configuration
+        .get(0)
+        .and_then(|stream_config| stream_config.stream())
+        .ok_or_else(|| eyre!("Camera {} did not create a stream", camera.id()))?;
+
+    let mut allocator = FrameBufferAllocator::new(&camera);
+    let buffers = allocator
+        .alloc(&stream)
+        .map_err(|e| eyre!("Failed to allocate frame buffers: {}", e))?;
+    let mut requests = Vec::with_capacity(buffers.len());
+    for (index, buffer) in buffers.into_iter().enumerate() {
+        let buffer = MemoryMappedFrameBuffer::new(buffer)
+            .map_err(|e| eyre!("Failed to map frame buffer: {:?}", e))?;
+        let mut request = camera
+            .create_request(Some(index as u64))
+            .ok_or_else(|| eyre!("Failed to create capture request"))?;
+        request.add_buffer(&stream, buffer)?;
+        requests.push(request);
+    }
+
+    let (completed_tx, completed) = mpsc::channel();
+    camera.on_request_completed(move |request| {
+        let _ = completed_tx.send(request);
+    });
+
+    // Pin the frame duration to the requested rate
+    let mut start_controls = ControlList::new();
+    let frame_duration_us = 1_000_000 / config.fps.max(1) as i64;
+    if let Err(e) = start_controls.set(controls::FrameDurationLimits([
+        frame_duration_us,
+        frame_duration_us,
+    ])) {
+        warn!(
+            "Failed to set {} fps on camera {}: {:?}",
+            config.fps,
+            camera.id(),
+            e
+        );
+    }
+    camera
+        .start(Some(&*start_controls))
+        .map_err(|e| eyre!("Failed to start camera {}: {}", camera.id(), e))?;
+    for request in requests {
+        camera.queue_request(request)?;
+    }
+
+    info!(
+        "libcamera capture on {}: {}x{} {:?}, {} buffers",
+        camera.id(),
+        size.width,
+        size.height,
+        config.format,
+        config.buffer_count
+    );
+    let _ = ready.send(());
+
+    let mut sequence = SequenceTracker::default();
+    let mut overruns = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        let mut request = match completed.recv_timeout(FRAME_TIMEOUT) {
+            Ok(request) => request,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(eyre!("Camera {} stopped delivering frames", camera.id()))
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let timestamp = Instant::now();
+
+        if request.status() == RequestStatus::Complete {
+            let buffer: &MemoryMappedFrameBuffer<FrameBuffer> = request
+                .buffer(&stream)
+                .ok_or_else(|| eyre!("Completed request has no buffer"))?;
+            let metadata = buffer
+                .metadata()
+                .ok_or_else(|| eyre!("Completed buffer has no metadata"))?;
+
+            // Planes (e.g. NV12 luma and chroma) are packed back to back
+            let planes = metadata.planes();
+            let mut data = BytesMut::new();
+            for (index, plane) in buffer.data().into_iter().enumerate() {
+                let used = planes
+                    .get(index)
+                    .map_or(plane.len(), |meta| meta.bytes_used as usize)
+                    .min(plane.len());
+                data.extend_from_slice(&plane[..used]);
+            }
+
+            let sensor_ns = metadata.timestamp();
+            let frame = Frame {
+                data: data.freeze(),
+                meta: Arc::new(FrameMetadata {
+                    sequence: sequence.advance(metadata.sequence()),
+                    width: size.width,
+                    height: size.height,
+                    stride,
+                    format: config.format,
+                    device_timestamp: (sensor_ns != 0).then_some(Duration::from_nanos(sensor_ns)),
+                }),
+                timestamp,
+                dmabuf: None,
+            };
+
+            match frames.try_send(Ok(frame)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    debug!("Frame consumer is behind; dropping frame");
+                    metrics::counter!("apollo_capture_dropped_frames").increment(1);
+                    overruns += 1;
+                }
+                Err(TrySendError::Disconnected(_)) => break,
+            }
+            dropped.store(sequence.dropped() + overruns, Ordering::Relaxed);
+        }
+
+        request.reuse(ReuseFlag::REUSE_BUFFERS);
+        camera.queue_request(request)?;
+    }
+
+    camera
+        .stop()
+        .map_err(|e| eyre!("Failed to stop camera {}: {}", camera.id(), e))?;
+    Ok(())
+}
+
+/// Empty selectors match any camera; otherwise match the id or model
+fn camera_matches(camera: &Camera<'_>, selector: &str) -> bool {
+    if selector.is_empty() || camera.id().contains(selector) {
+        return true;
+    }
+    camera
+        .properties()
+        .get::<properties::Model>()
+        .is_ok_and(|model| model.contains(selector))
+}
+
+/// libcamera uses DRM fourccs, which name packed RGB from the most significant byte
+fn camera_format(format: PixelFormat) -> CameraFormat {
+    let fourcc = match format {
+        PixelFormat::Rgb24 => *b"BG24",
+        PixelFormat::Bgr24 => *b"RG24",
+        PixelFormat::Yuyv4 => *b"YUYV",
+        PixelFormat::Mjpeg => *b"MJPG",
+        PixelFormat::Nv12 => *b"NV12",
+        PixelFormat::H264 => *b"H264",
+        PixelFormat::H265 => *b"HEVC",
+    };
+    CameraFormat::new(u32::from_le_bytes(fourcc), 0)
+}
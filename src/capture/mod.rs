@@ -1,3 +1,26 @@
+pub mod backend;
+mod convert;
+mod dmabuf;
+pub mod file;
 pub mod frame;
+pub mod image_sequence;
+#[cfg(feature = "libcamera")]
+pub mod libcamera;
+pub mod negotiate;
+pub mod network;
+mod sequence;
+pub mod source;
+pub mod test_pattern;
+pub mod v4l2;
 
-pub use frame::{Frame, PixelFormat};
+pub use backend::{open_source, CaptureBackend};
+pub use file::FileSource;
+pub use frame::{DmaBuf, Frame, PixelFormat};
+pub use image_sequence::ImageSequenceSource;
+#[cfg(feature = "libcamera")]
+pub use libcamera::LibcameraCapture;
+pub use negotiate::{fit_to_capture, negotiate_mode, ModePolicy};
+pub use network::{is_network_uri, NetworkStream};
+pub use source::CaptureSource;
+pub use test_pattern::TestPatternSource;
+pub use v4l2::V4l2Capture;
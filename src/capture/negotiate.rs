@@ -0,0 +1,340 @@
+//! Pick the supported device mode closest to the requested capture settings
+
+use std::cmp::Ordering;
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    capture::PixelFormat,
+    utils::{probe_device, DeviceInfo, DeviceMode},
+    CaptureConfig, Config,
+};
+
+/// How to choose a mode when the exact size/fps is unavailable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModePolicy {
+    /// Closest resolution, then closest frame rate
+    #[default]
+    PreferResolution,
+    /// Closest frame rate, then closest resolution
+    PreferFps,
+    /// Highest frame rate (shortest frame interval), then closest resolution
+    LowestLatency,
+}
+
+/// Query the device and rewrite `capture` to the mode it will actually deliver
+///
+/// Leaves the request untouched if the device cannot be queried or reports no
+/// modes, so pipelines on drivers without enumeration support still start.
+pub fn negotiate_mode(capture: &mut CaptureConfig) -> Result<()> {
+    let device = match probe_device(&capture.device.path) {
+        Ok(device) => device,
+        Err(e) => {
+            warn!("Skipping mode negotiation: {}", e);
+            return Ok(());
+        }
+    };
+
+    let Some(mode) = select_mode(capture, &device)? else {
+        warn!(
+            "{} reports no sizes for {:?}; using the requested mode",
+            device.path, capture.format
+        );
+        return Ok(());
+    };
+
+    // Drivers that do not enumerate intervals keep the requested rate
+    let fps = match mode.interval.0 {
+        0 => capture.fps,
+        _ => mode.fps().round() as u32,
+    };
+    if (mode.width, mode.height, fps) != (capture.width, capture.height, capture.fps) {
+        info!(
+            "{} does not support {}x{}@{} {:?}; using {}x{}@{:.2} ({:?})",
+            device.path,
+            capture.width,
+            capture.height,
+            capture.fps,
+            capture.format,
+            mode.width,
+            mode.height,
+            mode.fps(),
+            capture.mode_policy
+        );
+    }
+
+    capture.width = mode.width;
+    capture.height = mode.height;
+    capture.fps = fps;
+    // NTSC rates such as 30000/1001 only negotiate as the exact fraction
+    capture.frame_interval = (mode.interval.0 > 0).then_some(mode.interval);
+    Ok(())
+}
+
+/// Clamp the settings that depend on the negotiated capture mode
+///
+/// A substituted size or rate can leave `display.roi` outside the frame or
+/// `pipeline.target_latency_ms` under one frame period. Both are adjusted and
+/// logged instead of failing, since the request was for the closest mode.
+pub fn fit_to_capture(config: &mut Config) {
+    let capture = &config.capture;
+    if let Some(roi) = config.display.roi {
+        if !roi.fits(capture.width, capture.height) {
+            warn!(
+                "display.roi {}x{} at ({}, {}) does not fit the {}x{} capture; showing the whole frame",
+                roi.width, roi.height, roi.x, roi.y, capture.width, capture.height
+            );
+            config.display.roi = None;
+        }
+    }
+
+    if let (Some(frame_period_ms), Some(min_latency_ms)) =
+        (capture.frame_period_ms(), capture.min_latency_ms())
+    {
+        if config.pipeline.target_latency_ms < min_latency_ms {
+            warn!(
+                "pipeline.target_latency_ms {} is under one frame at {} fps; using {}",
+                config.pipeline.target_latency_ms, capture.fps, frame_period_ms
+            );
+            config.pipeline.target_latency_ms = frame_period_ms;
+        }
+    }
+}
+
+impl CaptureConfig {
+    /// Frames per second as (numerator, denominator): the negotiated interval
+    /// inverted, or `fps`/1
+    pub fn framerate(&self) -> (u32, u32) {
+        match self.frame_interval {
+            Some((numerator, denominator)) => (denominator, numerator),
+            None => (self.fps, 1),
+        }
+    }
+
+    /// Whole milliseconds per frame, `None` while `fps` is zero
+    pub fn frame_period_ms(&self) -> Option<u32> {
+        1000u32.checked_div(self.fps)
+    }
+
+    /// Shortest `pipeline.target_latency_ms` that still covers one frame
+    ///
+    /// Allows a millisecond for rounding the period, so whole values such as
+    /// 32 ms at 30 fps pass. `None` while `fps` is zero.
+    pub fn min_latency_ms(&self) -> Option<u32> {
+        self.frame_period_ms()
+            .map(|frame_period_ms| frame_period_ms.saturating_sub(1))
+    }
+}
+
+/// The best mode for the requested format under the configured policy
+pub fn select_mode<'a>(
+    capture: &CaptureConfig,
+    device: &'a DeviceInfo,
+) -> Result<Option<&'a DeviceMode>> {
+    let candidates: Vec<&DeviceMode> = device
+        .modes
+        .iter()
+        .filter(|mode| mode.format == Some(capture.format) && mode.has_size())
+        .collect();
+
+    if candidates.is_empty() {
+        let advertised = device.formats.iter().any(|(fourcc, _)| {
+            <[u8; 4]>::try_from(fourcc.as_bytes())
+                .is_ok_and(|fourcc| PixelFormat::from_fourcc(&fourcc) == Some(capture.format))
+        });
+        // Format listed but no sizes enumerated: nothing to choose between
+        if advertised {
+            return Ok(None);
+        }
+
+        let offered = device
+            .formats
+            .iter()
+            .map(|(fourcc, _)| fourcc.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(eyre!(
+            "{} does not support {:?} (offers: {})",
+            device.path,
+            capture.format,
+            offered
+        ));
+    }
+
+    let size_distance = |mode: &DeviceMode| {
+        mode.width.abs_diff(capture.width) as u64 + mode.height.abs_diff(capture.height) as u64
+    };
+    let fps_distance = |mode: &DeviceMode| (mode.fps() - capture.fps as f64).abs();
+
+    let compare = |a: &&DeviceMode, b: &&DeviceMode| -> Ordering {
+        match capture.mode_policy {
+            ModePolicy::PreferResolution => size_distance(a)
+                .cmp(&size_distance(b))
+                .then(fps_distance(a).total_cmp(&fps_distance(b))),
+            ModePolicy::PreferFps => fps_distance(a)
+                .total_cmp(&fps_distance(b))
+                .then(size_distance(a).cmp(&size_distance(b))),
+            ModePolicy::LowestLatency => b
+                .fps()
+                .total_cmp(&a.fps())
+                .then(size_distance(a).cmp(&size_distance(b))),
+        }
+    };
+
+    Ok(candidates.into_iter().min_by(compare))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(fourcc: &str, width: u32, height: u32, fps: u32) -> DeviceMode {
+        let format = <[u8; 4]>::try_from(fourcc.as_bytes())
+            .ok()
+            .and_then(|fourcc| PixelFormat::from_fourcc(&fourcc));
+        DeviceMode {
+            fourcc: fourcc.into(),
+            format,
+            width,
+            height,
+            interval: (1, fps),
+        }
+    }
+
+    /// MJPEG in four modes, one YUYV mode, and H.264 listed without sizes
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            path: "/dev/video0".into(),
+            card: "Test camera".into(),
+            driver: "uvcvideo".into(),
+            bus_info: "usb-0000:00:14.0-1".into(),
+            serial: None,
+            links: Vec::new(),
+            formats: vec![
+                ("MJPG".into(), "Motion-JPEG".into()),
+                ("YUYV".into(), "YUYV 4:2:2".into()),
+                ("H264".into(), "H.264".into()),
+            ],
+            modes: vec![
+                mode("MJPG", 1920, 1080, 30),
+                mode("MJPG", 1280, 720, 60),
+                mode("MJPG", 1280, 720, 30),
+                mode("MJPG", 640, 480, 120),
+                mode("YUYV", 640, 480, 30),
+            ],
+        }
+    }
+
+    fn request(
+        format: PixelFormat,
+        (width, height, fps): (u32, u32, u32),
+        policy: ModePolicy,
+    ) -> CaptureConfig {
+        let mut capture = crate::Config::default().capture;
+        capture.format = format;
+        capture.width = width;
+        capture.height = height;
+        capture.fps = fps;
+        capture.mode_policy = policy;
+        capture
+    }
+
+    fn selected(capture: &CaptureConfig) -> (u32, u32, f64) {
+        let device = device();
+        let mode = select_mode(capture, &device).unwrap().unwrap();
+        (mode.width, mode.height, mode.fps())
+    }
+
+    #[test]
+    fn prefer_resolution_then_closest_fps() {
+        let capture = request(
+            PixelFormat::Mjpeg,
+            (1280, 720, 50),
+            ModePolicy::PreferResolution,
+        );
+        assert_eq!(selected(&capture), (1280, 720, 60.0));
+    }
+
+    #[test]
+    fn prefer_fps_then_closest_resolution() {
+        let capture = request(PixelFormat::Mjpeg, (1920, 1080, 60), ModePolicy::PreferFps);
+        assert_eq!(selected(&capture), (1280, 720, 60.0));
+    }
+
+    #[test]
+    fn lowest_latency_takes_the_fastest_mode() {
+        let capture = request(
+            PixelFormat::Mjpeg,
+            (1920, 1080, 30),
+            ModePolicy::LowestLatency,
+        );
+        assert_eq!(selected(&capture), (640, 480, 120.0));
+    }
+
+    #[test]
+    fn only_modes_of_the_requested_format_count() {
+        let capture = request(
+            PixelFormat::Yuyv4,
+            (1920, 1080, 30),
+            ModePolicy::PreferResolution,
+        );
+        assert_eq!(selected(&capture), (640, 480, 30.0));
+    }
+
+    #[test]
+    fn advertised_format_without_sizes_selects_nothing() {
+        let capture = request(
+            PixelFormat::H264,
+            (1920, 1080, 30),
+            ModePolicy::PreferResolution,
+        );
+        assert_eq!(select_mode(&capture, &device()).unwrap(), None);
+    }
+
+    #[test]
+    fn fitting_clamps_latency_and_drops_an_outside_roi() {
+        let mut config = crate::Config::default();
+        config.capture.width = 640;
+        config.capture.height = 480;
+        config.capture.fps = 15;
+        config.display.roi = Some(crate::display::Roi {
+            x: 0,
+            y: 0,
+            width: 1280,
+            height: 720,
+        });
+        fit_to_capture(&mut config);
+        assert_eq!(config.display.roi, None);
+        assert_eq!(config.pipeline.target_latency_ms, 66);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn format_without_enumerated_sizes_selects_nothing() {
+        let mut device = device();
+        device.formats.push(("NV12".into(), "Y/UV 4:2:0".into()));
+        device.modes.push(DeviceMode {
+            interval: (0, 0),
+            ..mode("NV12", 0, 0, 0)
+        });
+        let capture = request(
+            PixelFormat::Nv12,
+            (1920, 1080, 30),
+            ModePolicy::PreferResolution,
+        );
+        assert_eq!(select_mode(&capture, &device).unwrap(), None);
+    }
+
+    #[test]
+    fn unsupported_format_lists_the_offered_ones() {
+        let capture = request(
+            PixelFormat::Nv12,
+            (1920, 1080, 30),
+            ModePolicy::PreferResolution,
+        );
+        let error = select_mode(&capture, &device()).unwrap_err().to_string();
+        assert!(error.contains("offers: MJPG, YUYV, H264"), "{}", error);
+    }
+}
@@ -0,0 +1,108 @@
+//! IP camera streams named by URI in `capture.device`
+
+use super::PixelFormat;
+use crate::CaptureConfig;
+
+/// A network stream the GStreamer backend can receive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkStream {
+    /// `rtsp://` (or `rtsps://`) camera carrying H.264
+    Rtsp(String),
+    /// `http://` (or `https://`) multipart MJPEG stream
+    HttpMjpeg(String),
+    /// `udp://host:port` RTP/H.264, as sent by `rtph264pay ! udpsink`
+    UdpRtp(String),
+}
+
+impl NetworkStream {
+    /// Classify a device string, or `None` if it is not a stream URI
+    pub fn parse(device: &str) -> Option<Self> {
+        let (scheme, _) = device.split_once("://")?;
+        let uri = device.to_string();
+        match scheme.to_ascii_lowercase().as_str() {
+            "rtsp" | "rtsps" => Some(NetworkStream::Rtsp(uri)),
+            "http" | "https" => Some(NetworkStream::HttpMjpeg(uri)),
+            "udp" => Some(NetworkStream::UdpRtp(uri)),
+            _ => None,
+        }
+    }
+
+    pub fn uri(&self) -> &str {
+        match self {
+            NetworkStream::Rtsp(uri)
+            | NetworkStream::HttpMjpeg(uri)
+            | NetworkStream::UdpRtp(uri) => uri,
+        }
+    }
+}
+
+/// Whether `device` is a stream URI rather than a local camera
+pub fn is_network_uri(device: &str) -> bool {
+    NetworkStream::parse(device).is_some()
+}
+
+impl CaptureConfig {
+    /// Whether frames arrive as JPEG: MJPEG cameras and HTTP MJPEG streams
+    ///
+    /// RTP and RTSP streams carry H.264 or H.265 whatever `format` says.
+    pub fn delivers_jpeg(&self) -> bool {
+        match NetworkStream::parse(&self.device.path) {
+            Some(stream) => matches!(stream, NetworkStream::HttpMjpeg(_)),
+            None => self.format == PixelFormat::Mjpeg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_stream_schemes() {
+        assert_eq!(
+            NetworkStream::parse("rtsp://camera.local/stream1"),
+            Some(NetworkStream::Rtsp("rtsp://camera.local/stream1".into()))
+        );
+        assert_eq!(
+            NetworkStream::parse("RTSPS://camera.local/stream1"),
+            Some(NetworkStream::Rtsp("RTSPS://camera.local/stream1".into()))
+        );
+        assert_eq!(
+            NetworkStream::parse("https://camera.local/video.mjpg"),
+            Some(NetworkStream::HttpMjpeg(
+                "https://camera.local/video.mjpg".into()
+            ))
+        );
+        assert_eq!(
+            NetworkStream::parse("udp://0.0.0.0:5000"),
+            Some(NetworkStream::UdpRtp("udp://0.0.0.0:5000".into()))
+        );
+    }
+
+    #[test]
+    fn rejects_local_devices_and_other_schemes() {
+        assert_eq!(NetworkStream::parse("/dev/video0"), None);
+        assert_eq!(NetworkStream::parse("imx219"), None);
+        assert_eq!(NetworkStream::parse("file:///tmp/clip.mp4"), None);
+        assert!(!is_network_uri(""));
+    }
+
+    #[test]
+    fn uri_is_the_device_string() {
+        let stream = NetworkStream::parse("udp://127.0.0.1:5004").unwrap();
+        assert_eq!(stream.uri(), "udp://127.0.0.1:5004");
+    }
+
+    #[test]
+    fn only_mjpeg_sources_deliver_jpeg() {
+        let mut capture = crate::Config::default().capture;
+        capture.format = PixelFormat::Mjpeg;
+        capture.device.path = "/dev/video0".into();
+        assert!(capture.delivers_jpeg());
+        capture.device.path = "rtsp://camera.local/stream1".into();
+        assert!(!capture.delivers_jpeg());
+        capture.device.path = "http://camera.local/video.mjpg".into();
+        capture.format = PixelFormat::H264;
+        assert!(capture.delivers_jpeg());
+    }
+}
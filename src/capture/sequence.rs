@@ -0,0 +1,84 @@
+//! Driver sequence numbers extended to 64 bits, with drop detection
+
+use tracing::debug;
+
+/// Turns per-frame driver sequence numbers into a monotonic count of frames
+#[derive(Debug, Default)]
+pub(super) struct SequenceTracker {
+    /// Driver sequence number of the previous frame
+    last: Option<u32>,
+    /// Monotonic sequence that keeps counting across driver wrap-around
+    sequence: u64,
+    dropped: u64,
+}
+
+impl SequenceTracker {
+    /// Record the next driver sequence number and return the extended one
+    pub(super) fn advance(&mut self, driver_sequence: u32) -> u64 {
+        let Some(last) = self.last.replace(driver_sequence) else {
+            self.sequence = driver_sequence as u64;
+            return self.sequence;
+        };
+
+        let step = driver_sequence.wrapping_sub(last);
+        // Wrap-around is a small forward step; anything past half the range went
+        // backwards because the driver restarted its count
+        if step > u32::MAX / 2 {
+            debug!("Driver sequence reset from {} to {}", last, driver_sequence);
+            self.sequence += 1;
+            return self.sequence;
+        }
+        if step > 1 {
+            let missed = (step - 1) as u64;
+            self.dropped += missed;
+            metrics::counter!("apollo_capture_dropped_frames").increment(missed);
+            debug!(
+                "Dropped {} frame(s) between sequence {} and {}",
+                missed, last, driver_sequence
+            );
+        }
+        self.sequence += step as u64;
+        self.sequence
+    }
+
+    /// Frames skipped by the driver so far
+    pub(super) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_skipped_frames() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.advance(10), 10);
+        assert_eq!(tracker.advance(11), 11);
+        assert_eq!(tracker.advance(14), 14);
+        assert_eq!(tracker.dropped(), 2);
+    }
+
+    #[test]
+    fn keeps_counting_across_wrap_around() {
+        let mut tracker = SequenceTracker::default();
+        tracker.advance(u32::MAX - 1);
+        tracker.advance(u32::MAX);
+        assert_eq!(tracker.advance(0), u32::MAX as u64 + 1);
+        assert_eq!(tracker.advance(2), u32::MAX as u64 + 3);
+        assert_eq!(tracker.dropped(), 1);
+    }
+
+    #[test]
+    fn backward_jump_rebaselines_without_drops() {
+        let mut tracker = SequenceTracker::default();
+        tracker.advance(500);
+        assert_eq!(tracker.advance(501), 501);
+        // Stream restarted: the driver counts from zero again
+        assert_eq!(tracker.advance(0), 502);
+        assert_eq!(tracker.advance(1), 503);
+        assert_eq!(tracker.advance(3), 505);
+        assert_eq!(tracker.dropped(), 1);
+    }
+}
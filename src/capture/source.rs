@@ -0,0 +1,99 @@
+//! The interface every frame producer implements
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+
+use super::frame::Frame;
+
+/// Something that produces [`Frame`]s in the configured format and size
+pub trait CaptureSource {
+    /// Block until the next frame is available
+    fn next_frame(&mut self) -> Result<Frame>;
+
+    /// Frames lost before reaching the consumer
+    fn dropped_frames(&self) -> u64 {
+        0
+    }
+}
+
+/// Paces synthetic sources to a fixed frame rate
+#[derive(Debug)]
+pub(super) struct FramePacer {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl FramePacer {
+    pub(super) fn new(fps: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / fps.max(1),
+            next: None,
+        }
+    }
+
+    /// Sleep until the next frame is due
+    ///
+    /// A consumer that falls more than a frame behind restarts the schedule
+    /// instead of being handed a burst of catch-up frames.
+    pub(super) fn wait(&mut self) {
+        let now = Instant::now();
+        let due = match self.next {
+            Some(due) if due > now => {
+                thread::sleep(due - now);
+                due
+            }
+            Some(due) if now - due < self.interval => due,
+            _ => now,
+        };
+        self.next = Some(due + self.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_follows_the_frame_rate() {
+        assert_eq!(FramePacer::new(25).interval, Duration::from_millis(40));
+        // A zero rate would divide by zero; it paces at one frame per second
+        assert_eq!(FramePacer::new(0).interval, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn waits_until_the_next_frame_is_due() {
+        let mut pacer = FramePacer::new(50);
+        pacer.wait();
+        let due = pacer.next.unwrap();
+
+        pacer.wait();
+        assert!(Instant::now() >= due);
+        assert_eq!(pacer.next, Some(due + pacer.interval));
+    }
+
+    #[test]
+    fn slightly_late_frames_keep_the_schedule() {
+        let mut pacer = FramePacer::new(10);
+        let due = Instant::now() - pacer.interval / 2;
+        pacer.next = Some(due);
+
+        pacer.wait();
+        assert_eq!(pacer.next, Some(due + pacer.interval));
+    }
+
+    #[test]
+    fn falling_behind_restarts_the_schedule() {
+        let mut pacer = FramePacer::new(10);
+        let before = Instant::now();
+        pacer.next = Some(before - pacer.interval * 5);
+
+        pacer.wait();
+        // No burst of catch-up frames: the next one is a full interval away
+        assert!(pacer.next.unwrap() >= before + pacer.interval);
+        assert!(before.elapsed() < pacer.interval);
+    }
+}
@@ -0,0 +1,207 @@
+//! Synthetic moving colour bars with the frame number burned in
+
+use std::{sync::Arc, time::Instant};
+
+use color_eyre::Result;
+
+use super::{
+    convert::encode_rgb,
+    frame::{Frame, FrameMetadata, PixelFormat},
+    source::{CaptureSource, FramePacer},
+};
+use crate::CaptureConfig;
+
+/// SMPTE-style bar colours, left to right
+const BARS: [[u8; 3]; 8] = [
+    [235, 235, 235],
+    [235, 235, 16],
+    [16, 235, 235],
+    [16, 235, 16],
+    [235, 16, 235],
+    [235, 16, 16],
+    [16, 16, 235],
+    [16, 16, 16],
+];
+
+/// 3x5 bitmap digits, one row per entry, most significant bit on the left
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Generates frames without any hardware, paced at the configured rate
+pub struct TestPatternSource {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    pacer: FramePacer,
+    sequence: u64,
+    /// Pixels the bars move per frame, so they cross the frame in about four seconds
+    speed: u32,
+}
+
+impl TestPatternSource {
+    pub fn new(config: &CaptureConfig) -> Self {
+        Self {
+            width: config.width,
+            height: config.height,
+            format: config.format,
+            pacer: FramePacer::new(config.fps),
+            sequence: 0,
+            speed: (config.width / (config.fps.max(1) * 4)).max(1),
+        }
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let bar_width = width.div_ceil(BARS.len()).max(1);
+        let shift = (self.sequence * self.speed as u64 % width.max(1) as u64) as usize;
+
+        let mut row = Vec::with_capacity(width * 3);
+        for x in 0..width {
+            row.extend_from_slice(&BARS[((x + width - shift) % width) / bar_width]);
+        }
+        let mut rgb = row.repeat(height);
+
+        self.draw_counter(&mut rgb);
+        rgb
+    }
+
+    /// Frame number in white on a black box in the top-left corner
+    fn draw_counter(&self, rgb: &mut [u8]) {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let digits: Vec<usize> = self
+            .sequence
+            .to_string()
+            .bytes()
+            .map(|digit| (digit - b'0') as usize)
+            .collect();
+        let scale = (height / 60).max(2);
+        let margin = scale * 2;
+        let box_width = (digits.len() * 4 + 1) * scale;
+        let box_height = 7 * scale;
+
+        for y in margin..(margin + box_height).min(height) {
+            for x in margin..(margin + box_width).min(width) {
+                let (cx, cy) = ((x - margin) / scale, (y - margin) / scale);
+                // One cell of padding around the text, one between digits
+                let lit = (1..=5).contains(&cy)
+                    && cx >= 1
+                    && (cx - 1) % 4 < 3
+                    && digits.get((cx - 1) / 4).is_some_and(|&digit| {
+                        DIGITS[digit][cy - 1] & (0b100 >> ((cx - 1) % 4)) != 0
+                    });
+                let value = if lit { 255 } else { 0 };
+                let offset = (y * width + x) * 3;
+                rgb[offset..offset + 3].fill(value);
+            }
+        }
+    }
+}
+
+impl CaptureSource for TestPatternSource {
+    fn next_frame(&mut self) -> Result<Frame> {
+        self.pacer.wait();
+        let timestamp = Instant::now();
+
+        let (data, stride) = encode_rgb(&self.render(), self.width, self.height, self.format)?;
+        let frame = Frame {
+            data,
+            meta: Arc::new(FrameMetadata {
+                sequence: self.sequence,
+                width: self.width,
+                height: self.height,
+                stride,
+                format: self.format,
+                device_timestamp: None,
+            }),
+            timestamp,
+            dmabuf: None,
+        };
+
+        self.sequence += 1;
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(fps: u32) -> CaptureConfig {
+        CaptureConfig {
+            width: 80,
+            height: 60,
+            fps,
+            format: PixelFormat::Rgb24,
+            ..crate::Config::default().capture
+        }
+    }
+
+    /// 80x60 at 10 fps: 10 pixel bars moving 2 pixels per frame, counter scale 2
+    fn source() -> TestPatternSource {
+        TestPatternSource::new(&config(10))
+    }
+
+    fn pixel(rgb: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * 80 + x) * 3;
+        [rgb[offset], rgb[offset + 1], rgb[offset + 2]]
+    }
+
+    #[test]
+    fn bars_shift_right_each_frame() {
+        let mut source = source();
+        assert_eq!(source.speed, 2);
+
+        let first = source.render();
+        assert_eq!(pixel(&first, 0, 59), BARS[0]);
+        assert_eq!(pixel(&first, 79, 59), BARS[7]);
+
+        source.sequence = 5;
+        let later = source.render();
+        for x in 0..80 {
+            assert_eq!(pixel(&later, x, 59), pixel(&first, (x + 80 - 10) % 80, 59));
+        }
+    }
+
+    #[test]
+    fn frame_number_is_burned_in() {
+        let mut source = source();
+        // Margin and scale are 4 and 2: digit cell (cx, cy) starts at 4 + 2 * cx
+        let cell = |rgb: &[u8], cx: usize, cy: usize| pixel(rgb, 4 + 2 * cx, 4 + 2 * cy);
+
+        let zero = source.render();
+        assert_eq!(cell(&zero, 0, 0), [0; 3]);
+        assert_eq!(cell(&zero, 1, 1), [255; 3]);
+        assert_eq!(cell(&zero, 2, 3), [0; 3]);
+
+        source.sequence = 1;
+        let one = source.render();
+        assert_eq!(cell(&one, 1, 1), [0; 3]);
+        assert_eq!(cell(&one, 2, 1), [255; 3]);
+
+        source.sequence = 10;
+        let ten = source.render();
+        // The second digit starts four cells later
+        assert_eq!(cell(&ten, 2, 1), [255; 3]);
+        assert_eq!(cell(&ten, 5, 1), [255; 3]);
+        assert_eq!(cell(&ten, 6, 3), [0; 3]);
+    }
+
+    #[test]
+    fn frames_are_numbered_in_sequence() {
+        let mut source = TestPatternSource::new(&config(1000));
+        let first = source.next_frame().unwrap();
+        let second = source.next_frame().unwrap();
+        assert_eq!((first.meta.sequence, second.meta.sequence), (0, 1));
+        assert_eq!(second.meta.stride, 80 * 3);
+    }
+}
@@ -0,0 +1,166 @@
+//! Native V4L2 streaming capture producing [`Frame`]s without GStreamer
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use color_eyre::{eyre::eyre, Result};
+use tracing::{debug, info, warn};
+use v4l::{
+    buffer::{Metadata, Type},
+    io::{mmap, traits::CaptureStream, userptr},
+    video::{capture::Parameters, Capture},
+    Device, Format, FourCC, Fraction,
+};
+
+use super::{
+    dmabuf::DmaBufStream,
+    frame::{Frame, FrameMetadata, PixelFormat},
+    sequence::SequenceTracker,
+    source::CaptureSource,
+};
+use crate::CaptureConfig;
+
+/// How long to wait for the driver to fill a buffer before giving up
+const DEQUEUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Stream {
+    Mmap(mmap::Stream<'static>),
+    UserPtr(userptr::Stream),
+    DmaBuf(DmaBufStream),
+}
+
+/// Capture from a V4L2 device using mmap, userptr or DMA-BUF buffer streaming
+pub struct V4l2Capture {
+    stream: Stream,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    stride: u32,
+    sequence: SequenceTracker,
+    // Declared last so the stream's buffers are released before the device closes
+    _device: Device,
+}
+
+impl V4l2Capture {
+    /// Open the configured device and negotiate format, size and frame rate
+    pub fn open(config: &CaptureConfig) -> Result<Self> {
+        let path = &config.device.path;
+        let device =
+            Device::with_path(path).map_err(|e| eyre!("Failed to open {}: {}", path, e))?;
+
+        let fourcc = FourCC::new(&config.format.fourcc());
+        let format = device
+            .set_format(&Format::new(config.width, config.height, fourcc))
+            .map_err(|e| eyre!("Failed to set format on {}: {}", path, e))?;
+        if format.fourcc != fourcc {
+            return Err(eyre!(
+                "{} does not support {:?} (driver chose {})",
+                path,
+                config.format,
+                format.fourcc
+            ));
+        }
+        if (format.width, format.height) != (config.width, config.height) {
+            warn!(
+                "{} adjusted {}x{} to {}x{}",
+                path, config.width, config.height, format.width, format.height
+            );
+        }
+
+        let (fps, per) = config.framerate();
+        match device.set_params(&Parameters::new(Fraction::new(per, fps))) {
+            Ok(params) => debug!("Frame interval: {}", params.interval),
+            Err(e) => warn!("Failed to set {} fps on {}: {}", config.fps, path, e),
+        }
+
+        let dmabuf = if config.use_dmabuf {
+            match DmaBufStream::with_buffers(&device, config.buffer_count, DEQUEUE_TIMEOUT) {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    warn!(
+                        "{} cannot export DMA-BUF ({}); falling back to mmap",
+                        path, e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let (stream, io_mode) = if let Some(stream) = dmabuf {
+            (Stream::DmaBuf(stream), "dmabuf")
+        } else if config.use_mmap || config.use_dmabuf {
+            let mut stream =
+                mmap::Stream::with_buffers(&device, Type::VideoCapture, config.buffer_count)
+                    .map_err(|e| eyre!("Failed to allocate mmap buffers: {}", e))?;
+            stream.set_timeout(DEQUEUE_TIMEOUT);
+            (Stream::Mmap(stream), "mmap")
+        } else {
+            let mut stream =
+                userptr::Stream::with_buffers(&device, Type::VideoCapture, config.buffer_count)
+                    .map_err(|e| eyre!("Failed to allocate userptr buffers: {}", e))?;
+            stream.set_timeout(DEQUEUE_TIMEOUT);
+            (Stream::UserPtr(stream), "userptr")
+        };
+        info!(
+            "V4L2 capture on {}: {}x{} {:?}, {} {} buffers",
+            path, format.width, format.height, config.format, config.buffer_count, io_mode
+        );
+
+        Ok(Self {
+            stream,
+            format: config.format,
+            width: format.width,
+            height: format.height,
+            stride: format.stride,
+            sequence: SequenceTracker::default(),
+            _device: device,
+        })
+    }
+}
+
+impl CaptureSource for V4l2Capture {
+    /// DMA-BUF frames carry no `data`; their buffer goes back to the driver
+    /// once the frame and everything wrapping it have been dropped.
+    fn next_frame(&mut self) -> Result<Frame> {
+        let copy = |(data, meta): (&[u8], &Metadata)| {
+            let used = (meta.bytesused as usize).min(data.len());
+            (Bytes::copy_from_slice(&data[..used]), None, *meta)
+        };
+        let (data, dmabuf, meta) = match &mut self.stream {
+            Stream::Mmap(stream) => stream.next().map(copy),
+            Stream::UserPtr(stream) => stream.next().map(copy),
+            Stream::DmaBuf(stream) => stream
+                .next()
+                .map(|(dmabuf, meta)| (Bytes::new(), Some(dmabuf), meta)),
+        }
+        .map_err(|e| eyre!("Failed to dequeue frame: {}", e))?;
+        let timestamp = Instant::now();
+
+        let sequence = self.sequence.advance(meta.sequence);
+        let device_timestamp = Duration::from(meta.timestamp);
+
+        Ok(Frame {
+            data,
+            meta: Arc::new(FrameMetadata {
+                sequence,
+                width: self.width,
+                height: self.height,
+                stride: self.stride,
+                format: self.format,
+                device_timestamp: (!device_timestamp.is_zero()).then_some(device_timestamp),
+            }),
+            timestamp,
+            dmabuf,
+        })
+    }
+
+    /// Frames the driver skipped, judged by gaps in its sequence numbers
+    fn dropped_frames(&self) -> u64 {
+        self.sequence.dropped()
+    }
+}
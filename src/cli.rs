@@ -0,0 +1,169 @@
+//! Command-line arguments for the apollo binary
+
+use std::path::PathBuf;
+
+use apollo::settings::ConfigSources;
+use clap::{Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "apollo",
+    version,
+    about = "High-performance camera streaming pipeline"
+)]
+pub struct Args {
+    /// Configuration file (defaults to ./apollo.toml when present)
+    #[arg(short, long, global = true, env = "APOLLO_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Override a configuration value, e.g. `--set capture.width=1280`
+    #[arg(
+        short = 's',
+        long = "set",
+        global = true,
+        value_name = "KEY=VALUE",
+        value_parser = parse_override
+    )]
+    pub overrides: Vec<(String, String)>,
+
+    /// What to do; defaults to `run`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Capture from the camera and display it (default)
+    Run(RunArgs),
+    /// List capture devices with every supported format, size and frame rate
+    ListDevices,
+    /// Show the identity and capture modes of a single device
+    Probe {
+        /// Device node, /dev/v4l link, card name substring, USB serial or bus path
+        device: String,
+    },
+    /// Print the effective merged configuration as TOML
+    PrintConfig,
+    /// Validate the configuration and GStreamer plugins without opening a window
+    Check,
+}
+
+/// Shorthands for the most common `--set` overrides
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct RunArgs {
+    /// Capture device: node, /dev/v4l link, card name substring, USB serial, bus path or
+    /// rtsp://, http:// or udp:// stream URI
+    #[arg(short, long)]
+    pub device: Option<String>,
+
+    /// Capture size as WIDTHxHEIGHT, e.g. 1280x720
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_size)]
+    pub size: Option<(u32, u32)>,
+
+    /// Capture frame rate
+    #[arg(long)]
+    pub fps: Option<u32>,
+
+    /// Capture pixel format: Mjpeg, H264, H265, Yuyv4, Rgb24, Bgr24 or Nv12
+    #[arg(long)]
+    pub format: Option<String>,
+
+    /// Video sink: Auto, Xvimage, Glimage, Wayland, Kms, Fake or File
+    #[arg(long)]
+    pub sink: Option<String>,
+
+    /// Scaling to the display: Stretch, Fit, Fill or Integer
+    #[arg(long)]
+    pub scaling: Option<String>,
+
+    /// Start recording to `recording.output_dir` right away
+    #[arg(long)]
+    pub record: bool,
+
+    /// Serve the feed over RTSP (needs `--features rtsp`)
+    #[arg(long)]
+    pub rtsp: bool,
+
+    /// Serve MJPEG over HTTP at /stream.mjpg and /snapshot.jpg
+    #[arg(long)]
+    pub http: bool,
+}
+
+impl Args {
+    pub fn command(&self) -> Command {
+        self.command
+            .clone()
+            .unwrap_or_else(|| Command::Run(RunArgs::default()))
+    }
+
+    /// Config layers from the flags; `run` shorthands win over `--set`
+    pub fn config_sources(&self) -> ConfigSources {
+        let mut overrides = self.overrides.clone();
+        if let Some(Command::Run(run)) = &self.command {
+            overrides.extend(run.overrides());
+        }
+
+        ConfigSources {
+            file: self.config.clone(),
+            overrides,
+        }
+    }
+}
+
+impl RunArgs {
+    fn overrides(&self) -> Vec<(String, String)> {
+        let mut overrides = Vec::new();
+        let mut set = |key: &str, value: String| overrides.push((key.to_string(), value));
+
+        if let Some(device) = &self.device {
+            set("capture.device", device.clone());
+        }
+        if let Some((width, height)) = self.size {
+            set("capture.width", width.to_string());
+            set("capture.height", height.to_string());
+        }
+        if let Some(fps) = self.fps {
+            set("capture.fps", fps.to_string());
+        }
+        if let Some(format) = &self.format {
+            set("capture.format", format.clone());
+        }
+        if let Some(sink) = &self.sink {
+            set("display.sink", sink.clone());
+        }
+        if let Some(scaling) = &self.scaling {
+            set("display.scaling", scaling.clone());
+        }
+        if self.record {
+            set("recording.enabled", "true".to_string());
+        }
+        if self.rtsp {
+            set("rtsp.enabled", "true".to_string());
+        }
+        if self.http {
+            set("http.enabled", "true".to_string());
+        }
+
+        overrides
+    }
+}
+
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", arg))
+}
+
+fn parse_size(arg: &str) -> Result<(u32, u32), String> {
+    let (width, height) = arg
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got `{}`", arg))?;
+    let parse = |value: &str| {
+        value
+            .trim()
+            .parse::<u32>()
+            .map_err(|e| format!("invalid size `{}`: {}", arg, e))
+    };
+    Ok((parse(width)?, parse(height)?))
+}
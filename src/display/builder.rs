@@ -0,0 +1,222 @@
+//! Assembles pipelines element by element so every stage is named and reachable
+
+use std::fmt;
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use thiserror::Error;
+use tracing::debug;
+
+/// Why a pipeline could not be assembled or negotiated
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("GStreamer element {factory} is not installed")]
+    MissingElement { factory: String },
+
+    #[error("Failed to add {name} to the pipeline: {source}")]
+    Add {
+        name: String,
+        source: gst::glib::BoolError,
+    },
+
+    #[error("Cannot link {upstream} to {downstream}: {upstream} offers {upstream_caps}; {downstream} accepts {downstream_caps}")]
+    Link {
+        upstream: String,
+        downstream: String,
+        upstream_caps: String,
+        downstream_caps: String,
+    },
+
+    #[error("Caps negotiation failed between {upstream} and {downstream}: {upstream} offers {upstream_caps}; {downstream} accepts {downstream_caps}")]
+    NotNegotiated {
+        upstream: String,
+        downstream: String,
+        upstream_caps: String,
+        downstream_caps: String,
+    },
+}
+
+/// Builds a linear pipeline, linking each stage to the one before it
+///
+/// Stages whose source pads only appear once data flows (`rtspsrc`,
+/// `decodebin`, `multipartdemux`) are linked when the pad is added.
+pub struct PipelineBuilder {
+    bin: gst::Bin,
+    stages: Vec<gst::Element>,
+}
+
+impl PipelineBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            bin: gst::Pipeline::with_name(name).upcast(),
+            stages: Vec::new(),
+        }
+    }
+
+    /// Build into a plain bin, e.g. a sink made of several elements
+    pub fn bin(name: &str) -> Self {
+        Self {
+            bin: gst::Bin::with_name(name),
+            stages: Vec::new(),
+        }
+    }
+
+    /// Create an element without adding it, e.g. to set properties first
+    pub fn element(factory: &str, name: &str) -> Result<gst::Element, BuildError> {
+        gst::ElementFactory::make(factory)
+            .name(name)
+            .build()
+            .map_err(|_| BuildError::MissingElement {
+                factory: factory.to_string(),
+            })
+    }
+
+    /// Create an element from `factory` and link it as the next stage
+    pub fn stage(&mut self, factory: &str, name: &str) -> Result<gst::Element, BuildError> {
+        let element = Self::element(factory, name)?;
+        self.push(element)
+    }
+
+    /// Add a capsfilter stage restricting the stream to `caps`
+    pub fn caps(&mut self, name: &str, caps: &gst::Caps) -> Result<gst::Element, BuildError> {
+        let filter = self.stage("capsfilter", name)?;
+        filter.set_property("caps", caps);
+        Ok(filter)
+    }
+
+    /// Add `element` and link it after the current last stage
+    pub fn push(&mut self, element: gst::Element) -> Result<gst::Element, BuildError> {
+        self.bin.add(&element).map_err(|source| BuildError::Add {
+            name: element.name().to_string(),
+            source,
+        })?;
+        if let Some(upstream) = self.stages.last() {
+            link(upstream, &element)?;
+        }
+        self.stages.push(element.clone());
+        Ok(element)
+    }
+
+    /// # Panics
+    ///
+    /// If the builder was created with [`PipelineBuilder::bin`].
+    pub fn finish(self) -> (gst::Pipeline, Chain) {
+        let pipeline = self
+            .bin
+            .downcast()
+            .expect("PipelineBuilder::new builds a pipeline");
+        (pipeline, Chain(self.stages))
+    }
+
+    /// The bin and its first stage, e.g. to add a ghost pad
+    pub fn finish_bin(self) -> (gst::Bin, Option<gst::Element>) {
+        let first = self.stages.into_iter().next();
+        (self.bin, first)
+    }
+}
+
+/// The stages of a built pipeline in link order
+#[derive(Clone)]
+pub struct Chain(Vec<gst::Element>);
+
+impl Chain {
+    /// The first link that has not agreed on caps, with what each side supports
+    ///
+    /// Call this before stopping the pipeline: caps are cleared on shutdown.
+    pub fn unnegotiated(&self) -> Option<BuildError> {
+        self.0.windows(2).find_map(|pair| {
+            let (upstream, downstream) = (&pair[0], &pair[1]);
+            let negotiated = upstream
+                .src_pads()
+                .first()
+                .is_some_and(|pad| pad.current_caps().is_some());
+            (!negotiated).then(|| BuildError::NotNegotiated {
+                upstream: upstream.name().to_string(),
+                downstream: downstream.name().to_string(),
+                upstream_caps: pad_caps(upstream.src_pads().first()),
+                downstream_caps: pad_caps(downstream.sink_pads().first()),
+            })
+        })
+    }
+}
+
+impl fmt::Display for Chain {
+    /// `gst-launch` style summary, e.g. `v4l2src name=source ! queue name=queue`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, element) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ! ")?;
+            }
+            let factory = element.factory().map(|factory| factory.name());
+            write!(
+                f,
+                "{} name={}",
+                factory.as_deref().unwrap_or("?"),
+                element.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn link(upstream: &gst::Element, downstream: &gst::Element) -> Result<(), BuildError> {
+    if upstream.link(downstream).is_ok() {
+        return Ok(());
+    }
+    if has_sometimes_src(upstream) {
+        link_on_pad_added(upstream, downstream);
+        return Ok(());
+    }
+
+    Err(BuildError::Link {
+        upstream: upstream.name().to_string(),
+        downstream: downstream.name().to_string(),
+        upstream_caps: pad_caps(upstream.src_pads().first()),
+        downstream_caps: pad_caps(downstream.sink_pads().first()),
+    })
+}
+
+fn has_sometimes_src(element: &gst::Element) -> bool {
+    element.pad_template_list().iter().any(|template| {
+        template.direction() == gst::PadDirection::Src
+            && template.presence() == gst::PadPresence::Sometimes
+    })
+}
+
+/// Link the first compatible pad `upstream` adds to `downstream`
+fn link_on_pad_added(upstream: &gst::Element, downstream: &gst::Element) {
+    let downstream = downstream.downgrade();
+    upstream.connect_pad_added(move |upstream, pad| {
+        let Some(downstream) = downstream.upgrade() else {
+            return;
+        };
+        let Some(sink) = downstream.sink_pads().into_iter().next() else {
+            return;
+        };
+        if sink.is_linked() {
+            return;
+        }
+        // Streams we don't want (e.g. RTSP audio) fail here and stay unlinked
+        if let Err(e) = pad.link(&sink) {
+            debug!(
+                "Not linking {}:{} to {}: {:?} ({})",
+                upstream.name(),
+                pad.name(),
+                downstream.name(),
+                e,
+                pad_caps(Some(pad))
+            );
+        }
+    });
+}
+
+/// Negotiated caps if any, otherwise everything the pad could handle
+fn pad_caps(pad: Option<&gst::Pad>) -> String {
+    match pad {
+        Some(pad) => pad
+            .current_caps()
+            .unwrap_or_else(|| pad.query_caps(None))
+            .to_string(),
+        None => "nothing (no pad yet)".to_string(),
+    }
+}
@@ -0,0 +1,184 @@
+//! User-supplied `gst-launch` descriptions from `gstreamer.custom_pipeline`
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use thiserror::Error;
+
+use crate::{CaptureConfig, DisplayConfig};
+
+/// Placeholders substituted into a custom pipeline
+pub const PLACEHOLDERS: [&str; 10] = [
+    "device",
+    "width",
+    "height",
+    "fps",
+    "format",
+    "caps",
+    "decoder",
+    "display_width",
+    "display_height",
+    "sink",
+];
+
+/// Why a custom pipeline cannot be used
+#[derive(Debug, Error)]
+pub enum CustomPipelineError {
+    #[error("unknown placeholder {{{name}}} in `gstreamer.custom_pipeline` (known: {})", PLACEHOLDERS.join(", "))]
+    UnknownPlaceholder { name: String },
+
+    #[error("unclosed `{{` in `gstreamer.custom_pipeline`")]
+    Unclosed,
+
+    #[error("`gstreamer.custom_pipeline` does not parse: {message}")]
+    Parse { message: String },
+
+    #[error("`gstreamer.custom_pipeline` is a single element, not a pipeline")]
+    NotAPipeline,
+}
+
+/// Substitute `{placeholder}`s; `{{` and `}}` stand for literal braces
+pub fn expand(
+    template: &str,
+    capture: &CaptureConfig,
+    display: &DisplayConfig,
+    decoder: &str,
+) -> Result<String, CustomPipelineError> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..open]);
+        let tail = &rest[open..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            expanded.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        if let Some(after) = tail.strip_prefix('}') {
+            expanded.push('}');
+            rest = after;
+            continue;
+        }
+
+        let close = tail.find('}').ok_or(CustomPipelineError::Unclosed)?;
+        let name = &tail[1..close];
+        let value = match name {
+            "device" => capture.device.path.clone(),
+            "width" => capture.width.to_string(),
+            "height" => capture.height.to_string(),
+            "fps" => capture.fps.to_string(),
+            // Raw formats by name; `jpeg`, `h264` or `h265` otherwise
+            "format" => match capture.format.gst_format() {
+                Some(format) => format.to_string(),
+                None => capture
+                    .format
+                    .gst_media_type()
+                    .rsplit(['/', '-'])
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+            },
+            "caps" => super::display::capture_caps(capture).to_string(),
+            "decoder" => decoder.to_string(),
+            "display_width" => display.width.to_string(),
+            "display_height" => display.height.to_string(),
+            "sink" => display.sink.factory().to_string(),
+            _ => {
+                return Err(CustomPipelineError::UnknownPlaceholder {
+                    name: name.to_string(),
+                })
+            }
+        };
+        expanded.push_str(&value);
+        rest = &tail[close + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Parse an expanded description into a stopped pipeline
+///
+/// Parsing creates and links every element, so a missing plugin or an
+/// incompatible link is reported here; pads that only appear once data flows
+/// are linked when the pipeline starts.
+pub fn parse(description: &str) -> Result<gst::Pipeline, CustomPipelineError> {
+    let mut context = gst::ParseContext::new();
+    let element = gst::parse::launch_full(
+        description,
+        Some(&mut context),
+        gst::ParseFlags::FATAL_ERRORS,
+    )
+    .map_err(|e| {
+        let missing = context.missing_elements();
+        CustomPipelineError::Parse {
+            message: if missing.is_empty() {
+                e.to_string()
+            } else {
+                format!("{} (missing: {})", e, missing.join(", "))
+            },
+        }
+    })?;
+
+    element
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| CustomPipelineError::NotAPipeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_with(template: &str) -> Result<String, CustomPipelineError> {
+        let mut config = crate::Config::default();
+        config.capture.device.path = "/dev/video2".into();
+        config.capture.width = 640;
+        expand(template, &config.capture, &config.display, "jpegdec")
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        assert_eq!(
+            expand_with("v4l2src device={device} ! {decoder} ! videoscale").unwrap(),
+            "v4l2src device=/dev/video2 ! jpegdec ! videoscale"
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(expand_with("{{").unwrap(), "{");
+        assert_eq!(expand_with("}}").unwrap(), "}");
+        assert_eq!(expand_with("{{width}}").unwrap(), "{width}");
+        assert_eq!(expand_with("{{{width}}}").unwrap(), "{640}");
+    }
+
+    #[test]
+    fn lone_closing_brace_is_kept() {
+        assert_eq!(expand_with("a } b").unwrap(), "a } b");
+    }
+
+    #[test]
+    fn rejects_unknown_and_unclosed_placeholders() {
+        assert!(matches!(
+            expand_with("{widht}"),
+            Err(CustomPipelineError::UnknownPlaceholder { name }) if name == "widht"
+        ));
+        assert!(matches!(
+            expand_with("videotestsrc ! {sink"),
+            Err(CustomPipelineError::Unclosed)
+        ));
+    }
+
+    #[test]
+    fn parses_pipelines_and_rejects_single_elements() {
+        gst::init().unwrap();
+        assert!(parse("videotestsrc ! fakesink").is_ok());
+        assert!(matches!(
+            parse("fakesink"),
+            Err(CustomPipelineError::NotAPipeline)
+        ));
+        assert!(matches!(
+            parse("videotestsrc ! nosuchelement ! fakesink"),
+            Err(CustomPipelineError::Parse { message }) if message.contains("nosuchelement")
+        ));
+    }
+}
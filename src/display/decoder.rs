@@ -0,0 +1,266 @@
+//! Decoder selection honouring the configured preference order and hardware opt-out
+
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+use tracing::{debug, info, warn};
+
+use super::builder::PipelineBuilder;
+use crate::GStreamerConfig;
+
+/// Always tried after the configured JPEG decoders
+pub const JPEG_SOFTWARE_DECODER: &str = "jpegdec";
+
+/// Default `gstreamer.jpeg_decoders`, hardware first
+pub const JPEG_DECODERS: [&str; 4] = ["nvjpegdec", "vaapijpegdec", "v4l2jpegdec", "jpegdec"];
+
+/// Always tried after the configured H.264 decoders
+pub const H264_SOFTWARE_DECODER: &str = "avdec_h264";
+
+/// Default `gstreamer.h264_decoders`, hardware first
+pub const H264_DECODERS: [&str; 5] = [
+    "nvh264dec",
+    "vah264dec",
+    "vaapih264dec",
+    "v4l2h264dec",
+    "avdec_h264",
+];
+
+/// Always tried after the configured H.265 decoders
+pub const H265_SOFTWARE_DECODER: &str = "avdec_h265";
+
+/// Default `gstreamer.h265_decoders`, hardware first
+pub const H265_DECODERS: [&str; 5] = [
+    "nvh265dec",
+    "vah265dec",
+    "vaapih265dec",
+    "v4l2h265dec",
+    "avdec_h265",
+];
+
+/// Size of the frame decoded by the preroll check; large enough for hardware minimums
+const TEST_FRAME_SIZE: (u32, u32) = (320, 240);
+
+/// How long a candidate gets to decode the test frame
+const PREROLL_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(2);
+
+/// The first usable JPEG decoder from `gstreamer.jpeg_decoders`
+///
+/// Each installed candidate must decode a test JPEG before it is chosen, which
+/// weeds out elements whose device is missing (e.g. `vaapijpegdec` without a
+/// VA driver). The software decoder is the last resort.
+pub fn select_jpeg_decoder(gstreamer: &GStreamerConfig) -> String {
+    let preferred: Vec<&str> = gstreamer.jpeg_decoders.iter().map(String::as_str).collect();
+    for decoder in candidates(&preferred, JPEG_SOFTWARE_DECODER, gstreamer) {
+        match preroll_test_jpeg(decoder) {
+            Ok(()) => {
+                info!("Using decoder: {}", decoder);
+                return decoder.to_string();
+            }
+            Err(e) => warn!("Decoder {} failed the preroll check: {}", decoder, e),
+        }
+    }
+
+    warn!(
+        "No JPEG decoder passed the preroll check; using {}",
+        JPEG_SOFTWARE_DECODER
+    );
+    JPEG_SOFTWARE_DECODER.to_string()
+}
+
+/// The first installed decoder from `gstreamer.h264_decoders`
+pub fn select_h264_decoder(gstreamer: &GStreamerConfig) -> String {
+    select_installed(&gstreamer.h264_decoders, H264_SOFTWARE_DECODER, gstreamer)
+}
+
+/// The first installed decoder from `gstreamer.h265_decoders`
+pub fn select_h265_decoder(gstreamer: &GStreamerConfig) -> String {
+    select_installed(&gstreamer.h265_decoders, H265_SOFTWARE_DECODER, gstreamer)
+}
+
+/// Inter-coded streams can't be checked with a single generated frame, so the
+/// first installed candidate wins
+fn select_installed(preferred: &[String], software: &str, gstreamer: &GStreamerConfig) -> String {
+    let preferred: Vec<&str> = preferred.iter().map(String::as_str).collect();
+    let decoder = candidates(&preferred, software, gstreamer)
+        .first()
+        .copied()
+        .unwrap_or(software);
+    info!("Using decoder: {}", decoder);
+    decoder.to_string()
+}
+
+/// Installed elements in preference order, ending with `software`
+pub(super) fn candidates<'a>(
+    preferred: &[&'a str],
+    software: &'a str,
+    gstreamer: &GStreamerConfig,
+) -> Vec<&'a str> {
+    order_candidates(
+        preferred,
+        software,
+        gstreamer.use_hardware_acceleration,
+        |name| gst::ElementFactory::find(name).map(|factory| is_hardware(&factory)),
+    )
+}
+
+/// `preferred`, then `software` unless listed, keeping what is installed
+///
+/// `installed` tells whether an element is a hardware one, or `None` when it
+/// is missing. Hardware elements are dropped unless `use_hardware` is set.
+fn order_candidates<'a>(
+    preferred: &[&'a str],
+    software: &'a str,
+    use_hardware: bool,
+    installed: impl Fn(&str) -> Option<bool>,
+) -> Vec<&'a str> {
+    let mut names = preferred.to_vec();
+    if !names.contains(&software) {
+        names.push(software);
+    }
+
+    names.retain(|name| match installed(name) {
+        None => {
+            debug!("{} is not installed", name);
+            false
+        }
+        Some(true) if !use_hardware => {
+            debug!("Skipping hardware element {}", name);
+            false
+        }
+        Some(_) => true,
+    });
+    names
+}
+
+/// Hardware elements say so in their klass, e.g. `Codec/Decoder/Video/Hardware`
+fn is_hardware(factory: &gst::ElementFactory) -> bool {
+    factory
+        .metadata(gst::ELEMENT_METADATA_KLASS)
+        .is_some_and(|klass| klass.contains("Hardware"))
+}
+
+/// Decode one generated JPEG through `decoder` into a fakesink
+fn preroll_test_jpeg(decoder: &str) -> Result<()> {
+    let (width, height) = TEST_FRAME_SIZE;
+    let mut builder = PipelineBuilder::new("decodercheck");
+    let source = builder.stage("appsrc", "source")?;
+    source.set_property(
+        "caps",
+        gst::Caps::builder("image/jpeg")
+            .field("width", width as i32)
+            .field("height", height as i32)
+            .field("framerate", gst::Fraction::new(0, 1))
+            .build(),
+    );
+    source.set_property("format", gst::Format::Time);
+    builder.stage(decoder, "decoder")?;
+    builder.stage("fakesink", "sink")?;
+    let (pipeline, _) = builder.finish();
+
+    let appsrc = source
+        .downcast::<gst_app::AppSrc>()
+        .map_err(|_| eyre!("appsrc is not an AppSrc"))?;
+    let result = decode_one(&pipeline, &appsrc);
+    pipeline.set_state(gst::State::Null).ok();
+    result
+}
+
+fn decode_one(pipeline: &gst::Pipeline, appsrc: &gst_app::AppSrc) -> Result<()> {
+    pipeline
+        .set_state(gst::State::Paused)
+        .map_err(|_| eyre!("failed to start"))?;
+
+    let mut buffer = gst::Buffer::from_slice(test_jpeg()?);
+    if let Some(buffer) = buffer.get_mut() {
+        buffer.set_pts(gst::ClockTime::ZERO);
+    }
+    appsrc
+        .push_buffer(buffer)
+        .map_err(|flow| eyre!("test frame rejected: {:?}", flow))?;
+    let _ = appsrc.end_of_stream();
+
+    // Prerolling completes once the decoded frame reaches the sink
+    let bus = pipeline.bus().ok_or_else(|| eyre!("pipeline has no bus"))?;
+    let msg = bus
+        .timed_pop_filtered(
+            PREROLL_TIMEOUT,
+            &[gst::MessageType::AsyncDone, gst::MessageType::Error],
+        )
+        .ok_or_else(|| eyre!("timed out decoding a test frame"))?;
+    match msg.view() {
+        gst::MessageView::Error(err) => Err(eyre!("{}", err.error())),
+        _ => Ok(()),
+    }
+}
+
+/// A small gradient encoded as a baseline JPEG
+fn test_jpeg() -> Result<Vec<u8>> {
+    let (width, height) = TEST_FRAME_SIZE;
+    let rgb: Vec<u8> = (0..height)
+        .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, 128]))
+        .collect();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 85).encode(
+        &rgb,
+        width,
+        height,
+        ExtendedColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// nvjpegdec is not installed; the VA-API and V4L2 decoders are hardware
+    fn installed(name: &str) -> Option<bool> {
+        match name {
+            "vaapijpegdec" | "v4l2jpegdec" => Some(true),
+            "jpegdec" | "avdec_h264" => Some(false),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn keeps_the_preference_order_of_installed_elements() {
+        assert_eq!(
+            order_candidates(&JPEG_DECODERS, JPEG_SOFTWARE_DECODER, true, installed),
+            ["vaapijpegdec", "v4l2jpegdec", "jpegdec"]
+        );
+        assert_eq!(
+            order_candidates(
+                &["jpegdec", "v4l2jpegdec"],
+                JPEG_SOFTWARE_DECODER,
+                true,
+                installed
+            ),
+            ["jpegdec", "v4l2jpegdec"]
+        );
+    }
+
+    #[test]
+    fn software_fallback_is_appended_once() {
+        assert_eq!(
+            order_candidates(&["v4l2jpegdec"], JPEG_SOFTWARE_DECODER, true, installed),
+            ["v4l2jpegdec", "jpegdec"]
+        );
+        assert_eq!(
+            order_candidates(&[], H264_SOFTWARE_DECODER, true, installed),
+            ["avdec_h264"]
+        );
+        assert!(order_candidates(&[], H265_SOFTWARE_DECODER, true, installed).is_empty());
+    }
+
+    #[test]
+    fn hardware_elements_are_skipped_without_acceleration() {
+        assert_eq!(
+            order_candidates(&JPEG_DECODERS, JPEG_SOFTWARE_DECODER, false, installed),
+            ["jpegdec"]
+        );
+    }
+}
@@ -1,14 +1,74 @@
 //! Simplified GStreamer display that ensures proper window sizing
 
+use std::sync::Arc;
+
 use color_eyre::{eyre::eyre, Result};
 use gstreamer as gst;
 use gstreamer::prelude::*;
-use tracing::info;
+use tracing::{info, warn};
+
+use super::{
+    builder::{BuildError, Chain, PipelineBuilder},
+    custom::{self, CustomPipelineError},
+    decoder::{select_h264_decoder, select_h265_decoder, select_jpeg_decoder},
+    flight::watch_trigger_key,
+    layout::ScalingStages,
+    native::spawn_feeder,
+    outputs::Outputs,
+    overlay::{self, StatsOverlay},
+    sink::video_sink,
+};
+use crate::{
+    capture::{CaptureBackend, NetworkStream, PixelFormat},
+    CaptureConfig, Config, DisplayConfig, GStreamerConfig,
+};
+
+/// Stage names, as shown in pipeline graphs and GStreamer debug logs
+const SOURCE_NAME: &str = "source";
+const DECODER_NAME: &str = "decoder";
+const JPEG_TEE_NAME: &str = "jpegtee";
+const RECORD_TEE_NAME: &str = "recordtee";
+const ROI_CROP_NAME: &str = "roicrop";
+const SCALER_NAME: &str = "scaler";
+const DISPLAY_CAPS_NAME: &str = "displaycaps";
+const LETTERBOX_NAME: &str = "letterbox";
+const STATS_OVERLAY_NAME: &str = "statsoverlay";
+const DISPLAY_SINK_NAME: &str = "displaysink";
+
+/// Jitter buffer for network streams, enough to absorb typical LAN jitter
+const NETWORK_LATENCY_MS: u32 = 50;
 
-use crate::{capture::PixelFormat, CaptureConfig, DisplayConfig};
+/// How long to wait on the bus before checking for a reloaded config
+const CONFIG_POLL_INTERVAL: gst::ClockTime = gst::ClockTime::from_mseconds(100);
+
+/// The display pipeline with handles to the stages adjusted at runtime
+pub struct DisplayPipeline {
+    pub pipeline: gst::Pipeline,
+    /// `v4l2src`, the native capture `appsrc` or a network source
+    pub source: gst::Element,
+    /// Present when the stream arrives compressed
+    pub decoder: Option<gst::Element>,
+    /// `tee` carrying the camera's JPEG frames, for MJPEG sources
+    pub jpeg_tee: Option<gst::Element>,
+    /// `tee` that encoded recordings branch from, carrying full-size frames
+    pub record_tee: gst::Element,
+    /// ROI crop, scaler, scaled-size capsfilter and letterbox
+    pub scaling: ScalingStages,
+    /// `textoverlay` drawing the pipeline statistics, silent unless enabled
+    pub stats_overlay: gst::Element,
+    /// `fpsdisplaysink` wrapping the configured video sink
+    pub sink: gst::Element,
+    chain: Chain,
+}
 
 /// Create and run a simple, properly-sized GStreamer pipeline
-pub fn run_pipeline(capture_config: &CaptureConfig, display_config: &DisplayConfig) -> Result<()> {
+///
+/// `gstreamer.custom_pipeline`, when set, replaces the built pipeline.
+pub fn run_pipeline(
+    capture_config: &CaptureConfig,
+    display_config: &DisplayConfig,
+    gstreamer_config: &GStreamerConfig,
+) -> Result<()> {
     // Initialize GStreamer
     gst::init().map_err(|e| eyre!("Failed to initialize GStreamer: {}", e))?;
 
@@ -17,37 +77,176 @@ pub fn run_pipeline(capture_config: &CaptureConfig, display_config: &DisplayConf
         display_config.width, display_config.height
     );
 
-    // Build a simpler pipeline that ensures window sizing
-    let pipeline_str = build_sized_pipeline(capture_config, display_config)?;
-    info!("Pipeline: {}", pipeline_str);
+    if let Some(template) = &gstreamer_config.custom_pipeline {
+        let pipeline =
+            launch_custom_pipeline(template, capture_config, display_config, gstreamer_config)?;
+        // Custom pipelines opt into runtime control by reusing the stage names
+        let mut live = LiveStages {
+            display_caps: pipeline.by_name(DISPLAY_CAPS_NAME),
+            scaling: None,
+            sink: pipeline.by_name(DISPLAY_SINK_NAME),
+            stats: None,
+            outputs: pipeline
+                .by_name(RECORD_TEE_NAME)
+                .map(|tee| Outputs::new(&pipeline, tee, pipeline.by_name(JPEG_TEE_NAME))),
+        };
+        let source = pipeline.by_name(SOURCE_NAME);
+        return play(&pipeline, source.as_ref(), capture_config, None, &mut live);
+    }
+
+    let built = build_display_pipeline(capture_config, display_config, gstreamer_config)?;
+    info!("Pipeline: {}", built.chain);
+    let captured = built
+        .pipeline
+        .by_name("queue")
+        .and_then(|queue| queue.static_pad("sink"))
+        .expect("built pipeline has a queue");
+    let stats = StatsOverlay::attach(
+        built.stats_overlay.clone(),
+        &captured,
+        built.sink.clone(),
+        built.decoder.as_ref(),
+    );
+    watch_trigger_key(&built.sink);
+    let mut live = LiveStages {
+        display_caps: None,
+        scaling: Some(built.scaling.clone()),
+        sink: Some(built.sink.clone()),
+        stats: Some(stats),
+        outputs: Some(Outputs::new(
+            &built.pipeline,
+            built.record_tee.clone(),
+            built.jpeg_tee.clone(),
+        )),
+    };
+    play(
+        &built.pipeline,
+        Some(&built.source),
+        capture_config,
+        Some(&built.chain),
+        &mut live,
+    )
+}
 
-    // Create and run the pipeline
-    let pipeline = gst::parse::launch(&pipeline_str)?;
+/// Stages a running pipeline adjusts when the config is reloaded
+struct LiveStages {
+    /// Resized to the display in custom pipelines
+    display_caps: Option<gst::Element>,
+    /// Re-laid out in built pipelines
+    scaling: Option<ScalingStages>,
+    sink: Option<gst::Element>,
+    /// Only built pipelines are measured
+    stats: Option<StatsOverlay>,
+    /// Custom pipelines record and stream when they name a `tee` like built ones
+    outputs: Option<Outputs>,
+}
 
+/// Start `pipeline` and run it until EOS or an error
+///
+/// `source` receives frames from native capture backends; `chain`, when
+/// known, pinpoints the link behind a negotiation failure.
+fn play(
+    pipeline: &gst::Pipeline,
+    source: Option<&gst::Element>,
+    capture_config: &CaptureConfig,
+    chain: Option<&Chain>,
+    live: &mut LiveStages,
+) -> Result<()> {
     // Set to PLAYING
     pipeline
         .set_state(gst::State::Playing)
         .map_err(|_| eyre!("Failed to start pipeline"))?;
 
-    // Wait for EOS or error
+    // Native backends capture outside GStreamer and push into the appsrc
+    let feeder = match capture_config.backend {
+        CaptureBackend::GStreamer => Ok(None),
+        backend => source
+            .ok_or_else(|| {
+                eyre!(
+                    "{:?} capture needs an appsrc named {}",
+                    backend,
+                    SOURCE_NAME
+                )
+            })
+            .and_then(|source| spawn_feeder(source, capture_config))
+            .map(Some),
+    };
+    let _feeder = match feeder {
+        Ok(feeder) => feeder,
+        Err(e) => {
+            pipeline.set_state(gst::State::Null).ok();
+            return Err(e);
+        }
+    };
+
+    // Wait for EOS or error, applying hot-reloaded settings in between
     let bus = pipeline.bus().unwrap();
-    for msg in bus.iter_timed(gst::ClockTime::NONE) {
-        use gst::MessageView;
-
-        match msg.view() {
-            MessageView::Eos(..) => break,
-            MessageView::Error(err) => {
-                pipeline.set_state(gst::State::Null).ok();
-                return Err(eyre!(
-                    "Pipeline error: {} ({})",
-                    err.error(),
-                    err.debug().unwrap_or_default()
-                ));
+    let mut applied = crate::CONFIG.load_full();
+    if applied.recording.enabled {
+        start_recording(live, &applied);
+    }
+    if applied.recording.flight_recorder {
+        arm_flight_recorder(live, &applied);
+    }
+    #[cfg(feature = "rtsp")]
+    if applied.rtsp.enabled {
+        start_rtsp(live, &applied);
+    }
+    if applied.http.enabled {
+        publish_mjpeg(live, &applied);
+    }
+    loop {
+        if let Some(msg) = bus.timed_pop(CONFIG_POLL_INTERVAL) {
+            use gst::MessageView;
+
+            // Recording failures stop the recording, not the display
+            let handled = live
+                .outputs
+                .as_mut()
+                .is_some_and(|outputs| outputs.handle_message(&msg));
+            match msg.view() {
+                _ if handled => {}
+                MessageView::Eos(..) => break,
+                MessageView::Error(err) => {
+                    // Caps are cleared on shutdown, so find the failed link first
+                    let unnegotiated = chain
+                        .filter(|_| is_not_negotiated(err))
+                        .and_then(Chain::unnegotiated);
+                    if let Some(outputs) = &mut live.outputs {
+                        outputs.finish(&bus);
+                    }
+                    pipeline.set_state(gst::State::Null).ok();
+                    if let Some(link) = unnegotiated {
+                        return Err(eyre!("Pipeline error: {}", link));
+                    }
+                    return Err(eyre!(
+                        "Pipeline error: {} ({})",
+                        err.error(),
+                        err.debug().unwrap_or_default()
+                    ));
+                }
+                _ => {}
             }
-            _ => {}
+        }
+
+        if let Some(stats) = &mut live.stats {
+            stats.refresh();
+        }
+        if let Some(outputs) = &mut live.outputs {
+            outputs.poll();
+        }
+
+        let current = crate::CONFIG.load_full();
+        if !Arc::ptr_eq(&current, &applied) {
+            apply_live_config(live, &applied, &current);
+            applied = current;
         }
     }
 
+    // Recordings and saved events need their index or trailer written first
+    if let Some(outputs) = &mut live.outputs {
+        outputs.finish(&bus);
+    }
     pipeline
         .set_state(gst::State::Null)
         .map_err(|_| eyre!("Failed to stop pipeline"))?;
@@ -55,65 +254,519 @@ pub fn run_pipeline(capture_config: &CaptureConfig, display_config: &DisplayConf
     Ok(())
 }
 
-fn build_sized_pipeline(capture: &CaptureConfig, display: &DisplayConfig) -> Result<String> {
-    let device = &capture.device.path;
-
-    // Detect the best decoder
-    let decoder = detect_best_decoder(capture);
-
-    // Build pipeline ensuring proper window size
-    // The key is to make sure we scale to the desired display size
-    let pipeline = match capture.format {
-        PixelFormat::Mjpeg => {
-            format!(
-                "v4l2src device={} ! \
-                 image/jpeg,width={},height={},framerate={}/1 ! \
-                 queue ! \
-                 {} ! \
-                 videoconvert ! \
-                 videoscale ! \
-                 video/x-raw,width={},height={} ! \
-                 fpsdisplaysink video-sink=\"xvimagesink force-aspect-ratio=false\" sync=false",
-                device,
-                capture.width,
-                capture.height,
-                capture.fps,
-                decoder,
-                display.width,
-                display.height
-            )
-        }
-        _ => {
-            format!(
-                "v4l2src device={} ! \
-                 video/x-raw,width={},height={},framerate={}/1 ! \
-                 queue ! \
-                 videoconvert ! \
-                 videoscale ! \
-                 video/x-raw,width={},height={} ! \
-                 fpsdisplaysink video-sink=\"xvimagesink force-aspect-ratio=false\" sync=false",
-                device, capture.width, capture.height, capture.fps, display.width, display.height
-            )
+/// Expand and parse `gstreamer.custom_pipeline` without starting it
+fn launch_custom_pipeline(
+    template: &str,
+    capture: &CaptureConfig,
+    display: &DisplayConfig,
+    gstreamer: &GStreamerConfig,
+) -> Result<gst::Pipeline, CustomPipelineError> {
+    let decoder = stream_decoder(capture, gstreamer);
+    let description = custom::expand(
+        template,
+        capture,
+        display,
+        decoder.as_deref().unwrap_or("identity"),
+    )?;
+    info!("Custom pipeline: {}", description);
+    custom::parse(&description)
+}
+
+/// Sources report failed negotiation as a generic flow error naming the reason
+fn is_not_negotiated(err: &gst::message::Error) -> bool {
+    err.debug()
+        .is_some_and(|debug| debug.contains("not-negotiated"))
+}
+
+fn start_recording(live: &mut LiveStages, config: &Config) {
+    let Some(outputs) = &mut live.outputs else {
+        warn!(
+            "Recording is enabled but the pipeline has no {}",
+            RECORD_TEE_NAME
+        );
+        return;
+    };
+    if let Err(e) = outputs.start_recording(&config.recording, &config.gstreamer) {
+        warn!("Failed to start recording: {}", e);
+    }
+}
+
+fn arm_flight_recorder(live: &mut LiveStages, config: &Config) {
+    let Some(outputs) = &mut live.outputs else {
+        warn!(
+            "The flight recorder is enabled but the pipeline has no {}",
+            RECORD_TEE_NAME
+        );
+        return;
+    };
+    if let Err(e) = outputs.arm(&config.recording, &config.gstreamer) {
+        warn!("Failed to start the flight recorder: {}", e);
+    }
+}
+
+fn publish_mjpeg(live: &mut LiveStages, config: &Config) {
+    let Some(outputs) = &mut live.outputs else {
+        warn!(
+            "HTTP output is enabled but the pipeline has no {}",
+            RECORD_TEE_NAME
+        );
+        return;
+    };
+    if let Err(e) = outputs.publish_mjpeg(&config.http) {
+        warn!("Failed to publish frames over HTTP: {}", e);
+    }
+}
+
+#[cfg(feature = "rtsp")]
+fn start_rtsp(live: &mut LiveStages, config: &Config) {
+    let Some(outputs) = &mut live.outputs else {
+        warn!(
+            "RTSP output is enabled but the pipeline has no {}",
+            RECORD_TEE_NAME
+        );
+        return;
+    };
+    if let Err(e) = outputs.serve(&config.rtsp, &config.gstreamer) {
+        warn!("Failed to start the RTSP server: {}", e);
+    }
+}
+
+/// Apply the fields of a reloaded config that can change without a restart
+fn apply_live_config(live: &mut LiveStages, old: &Config, new: &Config) {
+    if old.recording.flight_recorder != new.recording.flight_recorder {
+        if new.recording.flight_recorder {
+            arm_flight_recorder(live, new);
+        } else if let Some(outputs) = &mut live.outputs {
+            outputs.disarm();
+        }
+    }
+    if old.recording.enabled != new.recording.enabled {
+        if new.recording.enabled {
+            start_recording(live, new);
+        } else if let Some(outputs) = &mut live.outputs {
+            outputs.stop_recording();
+        }
+    }
+
+    let layout =
+        |display: &DisplayConfig| (display.width, display.height, display.scaling, display.roi);
+    if let Some(scaling) = &live.scaling {
+        if layout(&old.display) != layout(&new.display) {
+            scaling.apply(&new.display);
+        }
+    } else if (old.display.width, old.display.height) != (new.display.width, new.display.height) {
+        match &live.display_caps {
+            Some(capsfilter) => {
+                info!(
+                    "Resizing display to {}x{}",
+                    new.display.width, new.display.height
+                );
+                capsfilter.set_property("caps", display_caps(&new.display));
+            }
+            None => warn!(
+                "Display size changed but the pipeline has no {}",
+                DISPLAY_CAPS_NAME
+            ),
+        }
+    }
+
+    if old.gstreamer.enable_fps_overlay != new.gstreamer.enable_fps_overlay {
+        if let Some(sink) = &live.sink {
+            info!(
+                "FPS overlay {}",
+                if new.gstreamer.enable_fps_overlay {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+            sink.set_property("text-overlay", new.gstreamer.enable_fps_overlay);
+        }
+    }
+
+    let overlay_changed = (
+        old.gstreamer.stats_overlay,
+        old.gstreamer.overlay_position,
+        old.gstreamer.overlay_font_size,
+    ) != (
+        new.gstreamer.stats_overlay,
+        new.gstreamer.overlay_position,
+        new.gstreamer.overlay_font_size,
+    );
+    if overlay_changed {
+        if let Some(stats) = &live.stats {
+            info!(
+                "Statistics overlay {}",
+                if new.gstreamer.stats_overlay {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+            stats.configure(&new.gstreamer);
+        }
+    }
+}
+
+fn display_caps(display: &DisplayConfig) -> gst::Caps {
+    gst::Caps::builder("video/x-raw")
+        .field("width", display.width as i32)
+        .field("height", display.height as i32)
+        .build()
+}
+
+/// Caps of the frames the capture side delivers
+pub(super) fn capture_caps(capture: &CaptureConfig) -> gst::Caps {
+    let mut builder = gst::Caps::builder(capture.format.gst_media_type());
+    if let Some(format) = capture.format.gst_format() {
+        builder = builder.field("format", format);
+    }
+    let (numerator, denominator) = capture.framerate();
+    builder
+        .field("width", capture.width as i32)
+        .field("height", capture.height as i32)
+        .field(
+            "framerate",
+            gst::Fraction::new(numerator as i32, denominator as i32),
+        )
+        .build()
+}
+
+/// Build the capture-to-display pipeline without starting it
+pub fn build_display_pipeline(
+    capture: &CaptureConfig,
+    display: &DisplayConfig,
+    gstreamer: &GStreamerConfig,
+) -> Result<DisplayPipeline, BuildError> {
+    let mut builder = PipelineBuilder::new("display");
+    let decoder = stream_decoder(capture, gstreamer);
+
+    // Network streams arrive encoded and are depayloaded and decoded here
+    let (source, (jpeg_tee, decoder)) = match network_stream(capture) {
+        Some(stream) => {
+            let source = add_network_source(&mut builder, &stream, capture.format)?;
+            let decoder = add_decoder(&mut builder, capture, decoder)?;
+            builder.stage("queue", "queue")?;
+            (source, decoder)
+        }
+        None => {
+            let source = match capture.backend {
+                CaptureBackend::GStreamer => v4l2_source(capture)?,
+                _ => appsrc_source(capture)?,
+            };
+            builder.push(source.clone())?;
+            builder.caps("capturecaps", &capture_caps(capture))?;
+            builder.stage("queue", "queue")?;
+            if capture.format.is_inter_coded() {
+                builder.stage(video_codec(capture.format).parse, "parse")?;
+            }
+
+            let decoder = add_decoder(&mut builder, capture, decoder)?;
+            (source, decoder)
+        }
+    };
+
+    // Branch off recordings, scale to the display size, draw the statistics, then
+    // show with the FPS overlay
+    builder.stage("videoconvert", "convert")?;
+    let record_tee = builder.stage("tee", RECORD_TEE_NAME)?;
+    let scaling = ScalingStages::add(
+        &mut builder,
+        [
+            ROI_CROP_NAME,
+            SCALER_NAME,
+            DISPLAY_CAPS_NAME,
+            LETTERBOX_NAME,
+        ],
+        (capture.width, capture.height),
+        display,
+    )?;
+    let stats_overlay = overlay::add_stats_overlay(&mut builder, STATS_OVERLAY_NAME, gstreamer)?;
+    let sink = PipelineBuilder::element("fpsdisplaysink", DISPLAY_SINK_NAME)?;
+    sink.set_property("video-sink", video_sink(display.sink.resolve(), display)?);
+    sink.set_property("text-overlay", gstreamer.enable_fps_overlay);
+    sink.set_property("sync", false);
+    let sink = builder.push(sink)?;
+
+    let (pipeline, chain) = builder.finish();
+    Ok(DisplayPipeline {
+        pipeline,
+        source,
+        decoder,
+        jpeg_tee,
+        record_tee,
+        scaling,
+        stats_overlay,
+        sink,
+        chain,
+    })
+}
+
+/// The decoder stage, preceded for JPEG sources by a `tee` for passthrough outputs
+fn add_decoder(
+    builder: &mut PipelineBuilder,
+    capture: &CaptureConfig,
+    decoder: Option<String>,
+) -> Result<(Option<gst::Element>, Option<gst::Element>), BuildError> {
+    let Some(decoder) = decoder else {
+        return Ok((None, None));
+    };
+    let jpeg_tee = capture
+        .delivers_jpeg()
+        .then(|| builder.stage("tee", JPEG_TEE_NAME))
+        .transpose()?;
+    let decoder = builder.stage(&decoder, DECODER_NAME)?;
+    Ok((jpeg_tee, Some(decoder)))
+}
+
+/// The stream named by `capture.device`, when the GStreamer backend receives one
+fn network_stream(capture: &CaptureConfig) -> Option<NetworkStream> {
+    match capture.backend {
+        CaptureBackend::GStreamer => NetworkStream::parse(&capture.device.path),
+        _ => None,
+    }
+}
+
+/// Elements specific to an H.264 or H.265 stream
+struct VideoCodec {
+    depay: &'static str,
+    parse: &'static str,
+    /// RTP `encoding-name`
+    encoding: &'static str,
+}
+
+/// RTP streams are H.264 unless `capture.format` says H.265
+fn video_codec(format: PixelFormat) -> VideoCodec {
+    match format {
+        PixelFormat::H265 => VideoCodec {
+            depay: "rtph265depay",
+            parse: "h265parse",
+            encoding: "H265",
+        },
+        _ => VideoCodec {
+            depay: "rtph264depay",
+            parse: "h264parse",
+            encoding: "H264",
+        },
+    }
+}
+
+/// Receive and depayload a network stream, up to its decoder
+fn add_network_source(
+    builder: &mut PipelineBuilder,
+    stream: &NetworkStream,
+    format: PixelFormat,
+) -> Result<gst::Element, BuildError> {
+    let codec = video_codec(format);
+    let source = match stream {
+        NetworkStream::Rtsp(uri) => {
+            let source = builder.stage("rtspsrc", SOURCE_NAME)?;
+            source.set_property("location", uri);
+            source.set_property("latency", NETWORK_LATENCY_MS);
+            builder.stage(codec.depay, "depay")?;
+            builder.stage(codec.parse, "parse")?;
+            source
+        }
+        NetworkStream::HttpMjpeg(uri) => {
+            let source = builder.stage("souphttpsrc", SOURCE_NAME)?;
+            source.set_property("location", uri);
+            source.set_property("is-live", true);
+            source.set_property("do-timestamp", true);
+            builder.stage("multipartdemux", "demux")?;
+            builder.caps("jpegcaps", &gst::Caps::new_empty_simple("image/jpeg"))?;
+            source
+        }
+        NetworkStream::UdpRtp(uri) => {
+            let source = builder.stage("udpsrc", SOURCE_NAME)?;
+            source.set_property("uri", uri);
+            source.set_property(
+                "caps",
+                gst::Caps::builder("application/x-rtp")
+                    .field("media", "video")
+                    .field("clock-rate", 90000i32)
+                    .field("encoding-name", codec.encoding)
+                    .build(),
+            );
+            let jitter = builder.stage("rtpjitterbuffer", "jitterbuffer")?;
+            jitter.set_property("latency", NETWORK_LATENCY_MS);
+            builder.stage(codec.depay, "depay")?;
+            builder.stage(codec.parse, "parse")?;
+            source
         }
     };
+    Ok(source)
+}
 
-    Ok(pipeline)
+/// Elements a network stream needs besides its decoder
+fn network_elements(stream: &NetworkStream, format: PixelFormat) -> Vec<&'static str> {
+    let codec = video_codec(format);
+    match stream {
+        NetworkStream::Rtsp(_) => vec!["rtspsrc", codec.depay, codec.parse],
+        NetworkStream::HttpMjpeg(_) => vec!["souphttpsrc", "multipartdemux"],
+        NetworkStream::UdpRtp(_) => vec!["udpsrc", "rtpjitterbuffer", codec.depay, codec.parse],
+    }
 }
 
-fn detect_best_decoder(capture: &CaptureConfig) -> &'static str {
-    if capture.format != crate::capture::frame::PixelFormat::Mjpeg {
-        return "";
+/// `v4l2src` for the configured device, importing DMA-BUF when requested
+fn v4l2_source(capture: &CaptureConfig) -> Result<gst::Element, BuildError> {
+    let source = PipelineBuilder::element("v4l2src", SOURCE_NAME)?;
+    source.set_property("device", &capture.device.path);
+    if capture.use_dmabuf {
+        source.set_property_from_str("io-mode", "dmabuf");
     }
+    Ok(source)
+}
+
+/// Live `appsrc` that native capture backends push frames into
+fn appsrc_source(capture: &CaptureConfig) -> Result<gst::Element, BuildError> {
+    let source = PipelineBuilder::element("appsrc", SOURCE_NAME)?;
+    source.set_property("caps", capture_caps(capture));
+    source.set_property("is-live", true);
+    source.set_property("format", gst::Format::Time);
+    source.set_property("do-timestamp", true);
+    Ok(source)
+}
+
+/// Verify that every element the pipeline needs is installed and links, without starting it
+///
+/// A custom pipeline is expanded and parsed instead, reporting the first parse
+/// or link error.
+pub fn check_elements(
+    capture: &CaptureConfig,
+    display: &DisplayConfig,
+    gstreamer: &GStreamerConfig,
+) -> Result<()> {
+    gst::init().map_err(|e| eyre!("Failed to initialize GStreamer: {}", e))?;
 
-    // Try hardware decoders first
-    let decoders = ["nvjpegdec", "vaapijpegdec", "v4l2jpegdec", "jpegdec"];
+    if let Some(template) = &gstreamer.custom_pipeline {
+        launch_custom_pipeline(template, capture, display, gstreamer)?;
+        return Ok(());
+    }
 
-    for decoder in &decoders {
-        if gst::ElementFactory::find(decoder).is_some() {
-            info!("Using decoder: {}", decoder);
-            return decoder;
+    let mut required = vec![
+        "queue",
+        "videoconvert",
+        "tee",
+        "videocrop",
+        "videoscale",
+        "capsfilter",
+        "videobox",
+        "textoverlay",
+        "fpsdisplaysink",
+    ];
+    required.extend(display.sink.elements());
+    if let Some(stream) = network_stream(capture) {
+        required.extend(network_elements(&stream, capture.format));
+    } else {
+        match capture.backend {
+            CaptureBackend::GStreamer => required.push("v4l2src"),
+            CaptureBackend::File => {
+                required.extend(["appsrc", "filesrc", "decodebin", "videorate", "appsink"]);
+                if capture.format == PixelFormat::Mjpeg {
+                    required.push("jpegenc");
+                }
+            }
+            _ => required.push("appsrc"),
+        }
+        if capture.format.is_inter_coded() {
+            required.push(video_codec(capture.format).parse);
         }
     }
+    find_missing(required)?;
+
+    // Building picks and prerolls the decoder and links every stage, catching
+    // incompatible caps before the camera opens
+    build_display_pipeline(capture, display, gstreamer)?;
+    Ok(())
+}
+
+pub(super) fn find_missing(required: Vec<&str>) -> Result<()> {
+    let missing: Vec<&str> = required
+        .into_iter()
+        .filter(|name| gst::ElementFactory::find(name).is_none())
+        .collect();
+    if !missing.is_empty() {
+        return Err(eyre!("Missing GStreamer elements: {}", missing.join(", ")));
+    }
 
-    "jpegdec"
+    Ok(())
+}
+
+/// Decoder for whatever the configured source delivers, `None` for raw video
+fn stream_decoder(capture: &CaptureConfig, gstreamer: &GStreamerConfig) -> Option<String> {
+    match network_stream(capture) {
+        Some(NetworkStream::HttpMjpeg(_)) => Some(select_jpeg_decoder(gstreamer)),
+        Some(_) if capture.format == PixelFormat::H265 => Some(select_h265_decoder(gstreamer)),
+        Some(_) => Some(select_h264_decoder(gstreamer)),
+        None => match capture.format {
+            PixelFormat::Mjpeg => Some(select_jpeg_decoder(gstreamer)),
+            PixelFormat::H264 => Some(select_h264_decoder(gstreamer)),
+            PixelFormat::H265 => Some(select_h265_decoder(gstreamer)),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use gstreamer_app as gst_app;
+
+    use super::*;
+
+    /// H.264 sent over RTP to 127.0.0.1 comes out of the depayloader and parser
+    #[test]
+    fn receives_rtp_over_loopback() {
+        gst::init().unwrap();
+        let stream_elements =
+            network_elements(&NetworkStream::UdpRtp(String::new()), PixelFormat::H264);
+        let required = [
+            "videotestsrc",
+            "x264enc",
+            "rtph264pay",
+            "udpsink",
+            "appsink",
+        ]
+        .into_iter()
+        .chain(stream_elements)
+        .collect();
+        if let Err(e) = find_missing(required) {
+            eprintln!("Skipping the RTP loopback test: {}", e);
+            return;
+        }
+
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .and_then(|socket| socket.local_addr())
+            .unwrap()
+            .port();
+        let stream = NetworkStream::parse(&format!("udp://127.0.0.1:{}", port)).unwrap();
+
+        let mut builder = PipelineBuilder::new("receiver");
+        add_network_source(&mut builder, &stream, PixelFormat::H264).unwrap();
+        let sink = builder.stage("appsink", "sink").unwrap();
+        sink.set_property("sync", false);
+        let (receiver, _) = builder.finish();
+        receiver.set_state(gst::State::Playing).unwrap();
+
+        let sender = gst::parse::launch(&format!(
+            "videotestsrc is-live=true ! video/x-raw,width=320,height=240,framerate=30/1 \
+             ! x264enc tune=zerolatency key-int-max=15 ! rtph264pay config-interval=-1 \
+             ! udpsink host=127.0.0.1 port={}",
+            port
+        ))
+        .unwrap();
+        sender.set_state(gst::State::Playing).unwrap();
+
+        let sample = sink
+            .downcast::<gst_app::AppSink>()
+            .unwrap()
+            .try_pull_sample(gst::ClockTime::from_seconds(10));
+        sender.set_state(gst::State::Null).unwrap();
+        receiver.set_state(gst::State::Null).unwrap();
+
+        let sample = sample.expect("no frame arrived over RTP");
+        let caps = sample.caps().unwrap();
+        assert_eq!(caps.structure(0).unwrap().name(), "video/x-h264");
+    }
 }
@@ -0,0 +1,496 @@
+//! Flight recorder: keeps the last seconds of recorded frames in memory and saves
+//! them, plus what follows, when an event is triggered
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use gstreamer_video as gst_video;
+use tracing::{info, warn};
+
+use super::{
+    builder::PipelineBuilder,
+    outputs::{branch_bin, Branch, Output, Tees},
+    recording::{add_encoding, create_output_dir, muxable_caps, output_path},
+};
+use crate::{GStreamerConfig, RecordingConfig};
+
+/// Key on the video window that triggers an event
+pub const TRIGGER_KEY: &str = "space";
+
+/// How long a saved event gets to finish writing when the flight recorder stops
+const FINISH_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(3);
+
+static TRIGGERED: AtomicBool = AtomicBool::new(false);
+
+/// Save the flight recorder's last seconds, and the seconds that follow, to a file
+///
+/// Safe to call from any thread; the running pipeline picks it up within
+/// its config poll interval.
+pub fn trigger_event() {
+    info!("Event triggered");
+    TRIGGERED.store(true, Ordering::SeqCst);
+}
+
+fn take_trigger() -> bool {
+    TRIGGERED.swap(false, Ordering::SeqCst)
+}
+
+/// Trigger an event when [`TRIGGER_KEY`] is pressed on the video window
+pub(super) fn watch_trigger_key(sink: &gst::Element) {
+    let Some(pad) = sink.static_pad("sink") else {
+        return;
+    };
+    // Sinks send key presses upstream as navigation events
+    pad.add_probe(gst::PadProbeType::EVENT_UPSTREAM, |_, info| {
+        let key_pressed = info.event().is_some_and(|event| {
+            matches!(
+                gst_video::NavigationEvent::parse(event),
+                Ok(gst_video::NavigationEvent::KeyPress { key, .. }) if key == TRIGGER_KEY
+            )
+        });
+        if key_pressed {
+            trigger_event();
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Keeps the last seconds of encoded frames while armed
+#[derive(Default)]
+pub(super) struct FlightRecorder {
+    armed: Option<(Branch, EventBuffer)>,
+}
+
+impl FlightRecorder {
+    /// Start keeping the last `recording.pre_event_seconds` for triggered events
+    pub(super) fn arm(
+        &mut self,
+        tees: &Tees,
+        recording: &RecordingConfig,
+        gstreamer: &GStreamerConfig,
+    ) -> Result<()> {
+        if self.armed.is_some() {
+            return Ok(());
+        }
+
+        create_output_dir(recording)?;
+        let mut builder = PipelineBuilder::bin("flightrecorder");
+        let description = add_encoding(
+            &mut builder,
+            "flight",
+            recording.mode,
+            recording.codec,
+            recording.bitrate_kbps,
+            gstreamer,
+        )?;
+        let sink = builder.stage("appsink", "flightsink")?;
+        let bin = branch_bin(builder)?;
+        let events = EventBuffer::new(&sink, recording)?;
+
+        let branch = tees.attach(recording.mode.into(), bin)?;
+        self.armed = Some((branch, events));
+        info!(
+            "Flight recorder keeping {} s of {}",
+            recording.pre_event_seconds, description
+        );
+        Ok(())
+    }
+
+    /// Save an event for each trigger and finish saved events
+    pub(super) fn poll(&mut self) {
+        let triggered = take_trigger();
+        match &mut self.armed {
+            Some((_, events)) => {
+                if triggered {
+                    events.trigger();
+                }
+                events.poll();
+            }
+            None if triggered => warn!("Event triggered but the flight recorder is off"),
+            None => {}
+        }
+    }
+}
+
+impl Output for FlightRecorder {
+    const NAME: &'static str = "Flight recorder";
+
+    fn branch_mut(&mut self) -> Option<&mut Branch> {
+        self.armed.as_mut().map(|(branch, _)| branch)
+    }
+
+    /// Saves any event in progress first
+    fn take(&mut self) -> Option<Branch> {
+        let (branch, events) = self.armed.take()?;
+        info!("Flight recorder stopped");
+        events.finish();
+        Some(branch)
+    }
+}
+
+/// A file being written for one event
+struct Writer {
+    pipeline: gst::Pipeline,
+    source: gst_app::AppSrc,
+    path: String,
+}
+
+/// An event still taking in frames
+struct Event {
+    writer: Writer,
+    /// Subtracted from every timestamp so the file starts at zero
+    offset: gst::ClockTime,
+    /// Frames up to this timestamp belong to the event
+    until: gst::ClockTime,
+}
+
+struct State {
+    /// Oldest first, always starting at a keyframe
+    history: VecDeque<gst::Sample>,
+    pre_event: gst::ClockTime,
+    post_event: gst::ClockTime,
+    event: Option<Event>,
+    /// Writers sent EOS, waiting for their muxer to finish the file
+    closing: Vec<Writer>,
+}
+
+/// The frames behind the flight recorder's `appsink` and the events saved from them
+pub(super) struct EventBuffer {
+    state: Arc<Mutex<State>>,
+    recording: RecordingConfig,
+}
+
+impl EventBuffer {
+    /// Collect the samples reaching `sink`
+    pub(super) fn new(sink: &gst::Element, recording: &RecordingConfig) -> Result<Self> {
+        let state = Arc::new(Mutex::new(State {
+            history: VecDeque::new(),
+            pre_event: gst::ClockTime::from_seconds(recording.pre_event_seconds.into()),
+            post_event: gst::ClockTime::from_seconds(recording.post_event_seconds.into()),
+            event: None,
+            closing: Vec::new(),
+        }));
+
+        let appsink = sink
+            .clone()
+            .downcast::<gst_app::AppSink>()
+            .map_err(|_| eyre!("{} is not an appsink", sink.name()))?;
+        appsink.set_caps(Some(&muxable_caps(recording)));
+        appsink.set_property("sync", false);
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample({
+                    let state = state.clone();
+                    move |appsink| {
+                        let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        state.lock().unwrap().push(sample);
+                        Ok(gst::FlowSuccess::Ok)
+                    }
+                })
+                .build(),
+        );
+
+        Ok(Self {
+            state,
+            recording: recording.clone(),
+        })
+    }
+
+    /// Start saving an event, or extend the one in progress
+    pub(super) fn trigger(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        let Some(newest) = state.history.back().and_then(pts) else {
+            warn!("Event triggered before the flight recorder has any frames");
+            return;
+        };
+        let until = newest + state.post_event;
+        if let Some(event) = &mut state.event {
+            event.until = until;
+            info!("Extending event {}", event.writer.path);
+            return;
+        }
+
+        let (Some(caps), Some(offset)) = (
+            state.history.front().and_then(|s| s.caps_owned()),
+            state.history.front().and_then(pts),
+        ) else {
+            return;
+        };
+        let writer = match open_writer(&caps, &self.recording) {
+            Ok(writer) => writer,
+            Err(e) => {
+                warn!("Cannot save event: {}", e);
+                return;
+            }
+        };
+        info!(
+            "Saving event to {} from {} s before the trigger",
+            writer.path,
+            (newest - offset).seconds()
+        );
+
+        let mut event = Event {
+            writer,
+            offset,
+            until,
+        };
+        for sample in &state.history {
+            event.write(sample);
+        }
+        state.event = Some(event);
+    }
+
+    /// Finish writers whose muxer is done
+    pub(super) fn poll(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.closing.retain(|writer| {
+            let bus = writer.pipeline.bus().expect("pipelines have a bus");
+            match bus.pop_filtered(&[gst::MessageType::Eos, gst::MessageType::Error]) {
+                Some(msg) => {
+                    writer.close(&msg);
+                    false
+                }
+                None => true,
+            }
+        });
+    }
+
+    /// End the event in progress and wait for every writer to finish
+    pub(super) fn finish(self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(event) = state.event.take() {
+            let _ = event.writer.source.end_of_stream();
+            state.closing.push(event.writer);
+        }
+        for writer in state.closing.drain(..) {
+            let bus = writer.pipeline.bus().expect("pipelines have a bus");
+            match bus.timed_pop_filtered(
+                FINISH_TIMEOUT,
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            ) {
+                Some(msg) => writer.close(&msg),
+                None => {
+                    warn!("Event {} did not finish in time", writer.path);
+                    writer.pipeline.set_state(gst::State::Null).ok();
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    /// Add a frame, feed the event in progress and drop frames older than needed
+    fn push(&mut self, sample: gst::Sample) {
+        if self.history.is_empty() && !is_keyframe(&sample) {
+            return;
+        }
+
+        if let Some(event) = &mut self.event {
+            if pts(&sample).is_some_and(|pts| pts > event.until) {
+                let _ = event.writer.source.end_of_stream();
+                let event = self.event.take().expect("event in progress");
+                self.closing.push(event.writer);
+            } else {
+                event.write(&sample);
+            }
+        }
+
+        self.history.push_back(sample);
+        self.trim();
+    }
+
+    /// Keep `pre_event` seconds, cutting only at keyframes
+    fn trim(&mut self) {
+        let Some(newest) = self.history.back().and_then(pts) else {
+            return;
+        };
+        while let Some(next) = self.history.iter().skip(1).position(is_keyframe) {
+            let next = next + 1;
+            let covered = pts(&self.history[next])
+                .is_some_and(|start| newest.saturating_sub(start) >= self.pre_event);
+            if !covered {
+                break;
+            }
+            self.history.drain(..next);
+        }
+    }
+}
+
+impl Event {
+    fn write(&mut self, sample: &gst::Sample) {
+        let Some(mut buffer) = sample.buffer_owned() else {
+            return;
+        };
+        {
+            let buffer = buffer.make_mut();
+            let offset = self.offset;
+            buffer.set_pts(buffer.pts().map(|pts| pts.saturating_sub(offset)));
+            buffer.set_dts(buffer.dts().map(|dts| dts.saturating_sub(offset)));
+        }
+        if let Err(flow) = self.writer.source.push_buffer(buffer) {
+            warn!("Event {} refused a frame: {:?}", self.writer.path, flow);
+        }
+    }
+}
+
+impl Writer {
+    fn close(&self, msg: &gst::Message) {
+        match msg.view() {
+            gst::MessageView::Error(err) => {
+                warn!("Failed to save event {}: {}", self.path, err.error())
+            }
+            _ => info!("Saved event {}", self.path),
+        }
+        self.pipeline.set_state(gst::State::Null).ok();
+    }
+}
+
+/// appsrc ! muxer ! filesink for one event file
+fn open_writer(caps: &gst::Caps, recording: &RecordingConfig) -> Result<Writer> {
+    let path = output_path(recording, "apollo-event", "")?;
+    let mut builder = PipelineBuilder::new("event");
+    let source = builder.stage("appsrc", "eventsrc")?;
+    source.set_property("caps", caps);
+    source.set_property("format", gst::Format::Time);
+    // The buffered seconds arrive at once
+    source.set_property("max-bytes", 0u64);
+    builder.stage(recording.container.muxer(), "eventmux")?;
+    builder
+        .stage("filesink", "eventfile")?
+        .set_property("location", &path);
+    let (pipeline, _) = builder.finish();
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .map_err(|_| eyre!("Failed to start writing {}", path))?;
+    let source = source
+        .downcast::<gst_app::AppSrc>()
+        .map_err(|_| eyre!("appsrc is not an AppSrc"))?;
+    Ok(Writer {
+        pipeline,
+        source,
+        path,
+    })
+}
+
+fn pts(sample: &gst::Sample) -> Option<gst::ClockTime> {
+    sample.buffer().and_then(|buffer| buffer.pts())
+}
+
+fn is_keyframe(sample: &gst::Sample) -> bool {
+    sample
+        .buffer()
+        .is_some_and(|buffer| !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(pre_event_seconds: u64) -> State {
+        State {
+            history: VecDeque::new(),
+            pre_event: gst::ClockTime::from_seconds(pre_event_seconds),
+            post_event: gst::ClockTime::from_seconds(1),
+            event: None,
+            closing: Vec::new(),
+        }
+    }
+
+    fn sample(pts_ms: u64, keyframe: bool) -> gst::Sample {
+        let mut buffer = gst::Buffer::new();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(gst::ClockTime::from_mseconds(pts_ms));
+            if !keyframe {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+        gst::Sample::builder().buffer(&buffer).build()
+    }
+
+    fn history_ms(state: &State) -> Vec<u64> {
+        state
+            .history
+            .iter()
+            .filter_map(pts)
+            .map(|pts| pts.mseconds())
+            .collect()
+    }
+
+    /// An unstarted appsrc ! fakesink; the frames it refuses are only logged
+    fn writer() -> Writer {
+        let mut builder = PipelineBuilder::new("event");
+        let source = builder.stage("appsrc", "eventsrc").unwrap();
+        builder.stage("fakesink", "eventfile").unwrap();
+        let (pipeline, _) = builder.finish();
+        Writer {
+            pipeline,
+            source: source.downcast().unwrap(),
+            path: "event.mkv".into(),
+        }
+    }
+
+    #[test]
+    fn history_starts_at_a_keyframe() {
+        gst::init().unwrap();
+        let mut state = state(10);
+
+        state.push(sample(0, false));
+        state.push(sample(500, false));
+        assert!(state.history.is_empty());
+
+        state.push(sample(1000, true));
+        state.push(sample(1500, false));
+        assert_eq!(history_ms(&state), [1000, 1500]);
+    }
+
+    #[test]
+    fn trim_keeps_the_pre_event_seconds_from_a_keyframe() {
+        gst::init().unwrap();
+        let mut state = state(2);
+
+        // A keyframe every second, a delta frame in between
+        for pts_ms in (0..=5000).step_by(500) {
+            state.push(sample(pts_ms, pts_ms % 1000 == 0));
+        }
+        assert_eq!(history_ms(&state), [3000, 3500, 4000, 4500, 5000]);
+
+        // The next keyframe only becomes the start once it alone covers 2 s
+        state.push(sample(5500, false));
+        assert_eq!(history_ms(&state).first(), Some(&3000));
+        state.push(sample(6000, false));
+        assert_eq!(history_ms(&state), [4000, 4500, 5000, 5500, 6000]);
+    }
+
+    #[test]
+    fn event_closes_after_its_last_frame() {
+        gst::init().unwrap();
+        let mut state = state(10);
+        state.push(sample(0, true));
+        state.event = Some(Event {
+            writer: writer(),
+            offset: gst::ClockTime::ZERO,
+            until: gst::ClockTime::from_seconds(1),
+        });
+
+        state.push(sample(500, false));
+        state.push(sample(1000, false));
+        assert!(state.event.is_some());
+        assert!(state.closing.is_empty());
+
+        state.push(sample(1500, false));
+        assert!(state.event.is_none());
+        assert_eq!(state.closing.len(), 1);
+        assert_eq!(history_ms(&state), [0, 500, 1000, 1500]);
+    }
+}
@@ -0,0 +1,404 @@
+//! Embedded HTTP server streaming the feed as MJPEG to browsers and legacy tools
+
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use once_cell::sync::Lazy;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time::{sleep_until, timeout, Instant},
+};
+use tracing::{debug, info, warn};
+
+use super::{
+    builder::PipelineBuilder,
+    display::find_missing,
+    outputs::{branch_bin, Branch, Feed, Output, Tees},
+};
+use crate::{CaptureConfig, HttpConfig};
+
+/// Multipart boundary between the frames of `/stream.mjpg`
+const BOUNDARY: &str = "apolloframe";
+
+/// Longest request head accepted
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// How long a client gets to send its request, or to take one frame
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `/snapshot.jpg` waits for the pipeline to deliver a frame
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Latest JPEG frame; each client reads at its own pace and skips what it missed
+static FRAMES: Lazy<watch::Sender<Option<Bytes>>> = Lazy::new(|| watch::channel(None).0);
+
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Clients currently watching `/stream.mjpg`
+pub fn client_count() -> usize {
+    CLIENTS.load(Ordering::SeqCst)
+}
+
+/// Check that the MJPEG output can be built for `capture`
+pub fn check_http(capture: &CaptureConfig) -> Result<()> {
+    if capture.delivers_jpeg() {
+        find_missing(vec!["queue", "jpegparse", "appsink"])
+    } else {
+        find_missing(vec![
+            "queue",
+            "valve",
+            "videorate",
+            "videoconvert",
+            "jpegenc",
+            "appsink",
+        ])
+    }
+}
+
+/// Publishes JPEG frames to the HTTP clients while enabled
+#[derive(Default)]
+pub(super) struct MjpegOutput {
+    branch: Option<Branch>,
+}
+
+impl MjpegOutput {
+    /// Publish the camera's own JPEG frames for MJPEG sources, re-encoded otherwise
+    pub(super) fn publish(&mut self, tees: &Tees, http: &HttpConfig) -> Result<()> {
+        if self.branch.is_some() {
+            return Ok(());
+        }
+
+        let passthrough = tees.has_jpeg();
+        let mut builder = PipelineBuilder::bin("httpoutput");
+        let description = add_jpeg_stages(&mut builder, passthrough, http)?;
+        let sink = builder.stage("appsink", "httpsink")?;
+        let bin = branch_bin(builder)?;
+        publish_frames(&sink)?;
+
+        let feed = if passthrough {
+            Feed::Jpeg
+        } else {
+            Feed::Decoded
+        };
+        self.branch = Some(tees.attach(feed, bin)?);
+        info!("Publishing {} over HTTP", description);
+        Ok(())
+    }
+}
+
+impl Output for MjpegOutput {
+    const NAME: &'static str = "HTTP output";
+
+    fn branch_mut(&mut self) -> Option<&mut Branch> {
+        self.branch.as_mut()
+    }
+
+    fn take(&mut self) -> Option<Branch> {
+        self.branch.take()
+    }
+}
+
+/// queue ! jpegparse, or queue ! valve ! videorate ! videoconvert ! jpegenc,
+/// returning a description
+///
+/// The valve stops encoding while nobody is watching, and `videorate` drops
+/// frames above `http.max_fps` before they are encoded. Stage names start
+/// with `http`.
+fn add_jpeg_stages(
+    builder: &mut PipelineBuilder,
+    passthrough: bool,
+    http: &HttpConfig,
+) -> Result<String> {
+    // Drop frames for viewers rather than stall the display
+    builder
+        .stage("queue", "httpqueue")?
+        .set_property_from_str("leaky", "downstream");
+    if passthrough {
+        builder.stage("jpegparse", "httpparse")?;
+        return Ok("camera MJPEG without re-encoding".to_string());
+    }
+    let valve = builder.stage("valve", "httpvalve")?;
+    drop_while_unwatched(&valve);
+    let rate = builder.stage("videorate", "httprate")?;
+    rate.set_property("drop-only", true);
+    if http.max_fps > 0 {
+        rate.set_property("max-rate", http.max_fps as i32);
+    }
+    builder.stage("videoconvert", "httpconvert")?;
+    builder
+        .stage("jpegenc", "httpenc")?
+        .set_property("quality", http.jpeg_quality as i32);
+    Ok(format!("JPEG at quality {}", http.jpeg_quality))
+}
+
+/// Close `valve` whenever no client is subscribed to the frames
+fn drop_while_unwatched(valve: &gst::Element) {
+    let Some(sink) = valve.static_pad("sink") else {
+        return;
+    };
+    let valve = valve.downgrade();
+    sink.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+        let Some(valve) = valve.upgrade() else {
+            return gst::PadProbeReturn::Remove;
+        };
+        // Checked before the valve sees the buffer, so a new client gets this one
+        let unwatched = FRAMES.receiver_count() == 0;
+        if valve.property::<bool>("drop") != unwatched {
+            valve.set_property("drop", unwatched);
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Publish the JPEG frames reaching `sink` to the HTTP clients
+fn publish_frames(sink: &gst::Element) -> Result<()> {
+    let appsink = sink
+        .clone()
+        .downcast::<gst_app::AppSink>()
+        .map_err(|_| eyre!("{} is not an appsink", sink.name()))?;
+    appsink.set_caps(Some(&gst::Caps::new_empty_simple("image/jpeg")));
+    appsink.set_property("sync", false);
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::builder()
+            .new_sample(|appsink| {
+                let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                // Nobody to copy for
+                if FRAMES.receiver_count() == 0 {
+                    return Ok(gst::FlowSuccess::Ok);
+                }
+                let Some(buffer) = sample.buffer() else {
+                    return Ok(gst::FlowSuccess::Ok);
+                };
+                let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                FRAMES.send_replace(Some(Bytes::copy_from_slice(map.as_slice())));
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+    Ok(())
+}
+
+/// Serve `/stream.mjpg` and `/snapshot.jpg` on `http.port` until the process exits
+///
+/// Runs independently of the pipeline, so clients stay connected while the
+/// camera reconnects; they simply receive no frames in between.
+pub async fn serve_http(http: HttpConfig) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", http.port))
+        .await
+        .map_err(|e| eyre!("Cannot listen for HTTP on port {}: {}", http.port, e))?;
+    info!(
+        "Serving http://0.0.0.0:{}/stream.mjpg and /snapshot.jpg",
+        http.port
+    );
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to accept an HTTP client: {}", e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, peer).await {
+                debug!("HTTP client {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// What a request head asks for
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    Stream,
+    Snapshot,
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+}
+
+/// Route on the request line, ignoring any query string
+fn route(head: &str) -> Route {
+    let mut request = head.split_whitespace();
+    let (method, target) = (request.next(), request.next());
+    let path = target.map(|target| target.split('?').next().unwrap_or(target));
+
+    match (method, path) {
+        (Some("GET"), Some("/stream.mjpg")) => Route::Stream,
+        (Some("GET"), Some("/snapshot.jpg")) => Route::Snapshot,
+        (Some("GET"), Some(_)) => Route::NotFound,
+        (Some(_), Some(_)) => Route::MethodNotAllowed,
+        _ => Route::BadRequest,
+    }
+}
+
+/// Shortest time between two frames sent to one client, if capped
+fn frame_interval(max_fps: u32) -> Option<Duration> {
+    (max_fps > 0).then(|| Duration::from_secs(1) / max_fps)
+}
+
+async fn handle_client(mut stream: TcpStream, peer: SocketAddr) -> Result<()> {
+    let head = timeout(CLIENT_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| eyre!("timed out sending its request"))??;
+
+    match route(&head) {
+        Route::Stream => stream_frames(stream, peer).await,
+        Route::Snapshot => snapshot(stream).await,
+        Route::NotFound => respond(&mut stream, "404 Not Found", b"Not found\n").await,
+        Route::MethodNotAllowed => {
+            respond(&mut stream, "405 Method Not Allowed", b"Only GET\n").await
+        }
+        Route::BadRequest => respond(&mut stream, "400 Bad Request", b"Bad request\n").await,
+    }
+}
+
+/// Read up to the blank line ending the request head; the body is never needed
+async fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_BYTES {
+            return Err(eyre!("request head is too large"));
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(eyre!("closed before finishing its request"));
+        }
+        head.extend_from_slice(&chunk[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// `multipart/x-mixed-replace` of every frame, at most `http.max_fps` per second
+async fn stream_frames(mut stream: TcpStream, peer: SocketAddr) -> Result<()> {
+    let mut frames = FRAMES.subscribe();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-cache, no-store\r\n\
+         Connection: close\r\n\r\n",
+        BOUNDARY
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    let clients = CLIENTS.fetch_add(1, Ordering::SeqCst) + 1;
+    info!("MJPEG client {} connected ({} watching)", peer, clients);
+    let result = async {
+        loop {
+            frames.changed().await?;
+            let Some(frame) = frames.borrow_and_update().clone() else {
+                continue;
+            };
+            let sent = Instant::now();
+            let part = format!(
+                "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                frame.len()
+            );
+            timeout(CLIENT_TIMEOUT, async {
+                stream.write_all(part.as_bytes()).await?;
+                stream.write_all(&frame).await?;
+                stream.write_all(b"\r\n").await
+            })
+            .await
+            .map_err(|_| eyre!("stalled taking a frame"))??;
+
+            // Read on every frame so a reload changes the cap for connected clients
+            if let Some(interval) = frame_interval(crate::CONFIG.load().http.max_fps) {
+                // Frames published meanwhile collapse into the latest one
+                sleep_until(sent + interval).await;
+            }
+        }
+    }
+    .await;
+    let clients = CLIENTS.fetch_sub(1, Ordering::SeqCst) - 1;
+    info!("MJPEG client {} disconnected ({} watching)", peer, clients);
+    result
+}
+
+/// The next frame the pipeline delivers, as a single JPEG
+async fn snapshot(mut stream: TcpStream) -> Result<()> {
+    let mut frames = FRAMES.subscribe();
+    // Wait for a fresh frame; the last one may predate a reconnect
+    let frame = timeout(SNAPSHOT_TIMEOUT, async {
+        loop {
+            frames.changed().await?;
+            if let Some(frame) = frames.borrow_and_update().clone() {
+                return Ok::<_, watch::error::RecvError>(frame);
+            }
+        }
+    })
+    .await;
+    let Ok(Ok(frame)) = frame else {
+        return respond(&mut stream, "503 Service Unavailable", b"No frame\n").await;
+    };
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: image/jpeg\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-cache, no-store\r\n\
+         Connection: close\r\n\r\n",
+        frame.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_routed_on_method_and_path() {
+        let head = |line: &str| format!("{}\r\nHost: camera\r\n\r\n", line);
+
+        assert_eq!(route(&head("GET /stream.mjpg HTTP/1.1")), Route::Stream);
+        assert_eq!(
+            route(&head("GET /stream.mjpg?t=123 HTTP/1.1")),
+            Route::Stream
+        );
+        assert_eq!(route(&head("GET /snapshot.jpg HTTP/1.0")), Route::Snapshot);
+        assert_eq!(route(&head("GET / HTTP/1.1")), Route::NotFound);
+        assert_eq!(
+            route(&head("POST /stream.mjpg HTTP/1.1")),
+            Route::MethodNotAllowed
+        );
+        assert_eq!(route(&head("GET")), Route::BadRequest);
+        assert_eq!(route(""), Route::BadRequest);
+    }
+
+    #[test]
+    fn frame_rate_cap_spaces_frames_evenly() {
+        assert_eq!(frame_interval(0), None);
+        assert_eq!(frame_interval(1), Some(Duration::from_secs(1)));
+        assert_eq!(frame_interval(15), Some(Duration::from_nanos(66_666_666)));
+        assert_eq!(frame_interval(30), Some(Duration::from_nanos(33_333_333)));
+    }
+}
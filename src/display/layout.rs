@@ -0,0 +1,411 @@
+//! Fitting the captured frame to the display: region of interest, scaling and borders
+
+use std::sync::{Arc, Mutex};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::builder::{BuildError, PipelineBuilder};
+use crate::DisplayConfig;
+
+/// How frames are scaled to the display size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScalingMode {
+    /// Fill the display, distorting the aspect ratio
+    Stretch,
+    /// Whole frame, letterboxed or pillarboxed with black bars
+    #[default]
+    Fit,
+    /// Cover the display, cropping the centre of the frame
+    Fill,
+    /// Whole-number multiples (or divisors) of the frame size, sharp pixels, black bars
+    Integer,
+}
+
+/// Region of the captured frame to show, in capture pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Roi {
+    /// Whether the region lies inside a `width`x`height` frame
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        self.x
+            .checked_add(self.width)
+            .is_some_and(|right| right <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|bottom| bottom <= height)
+    }
+}
+
+/// Edge amounts in GStreamer's left, right, top, bottom order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Edges {
+    left: i32,
+    right: i32,
+    top: i32,
+    bottom: i32,
+}
+
+impl Edges {
+    /// Split `horizontal` and `vertical` evenly between opposite edges
+    fn centred(horizontal: i32, vertical: i32) -> Self {
+        Self {
+            left: horizontal / 2,
+            right: horizontal - horizontal / 2,
+            top: vertical / 2,
+            bottom: vertical - vertical / 2,
+        }
+    }
+
+    fn set_on(self, element: &gst::Element) {
+        element.set_property("left", self.left);
+        element.set_property("right", self.right);
+        element.set_property("top", self.top);
+        element.set_property("bottom", self.bottom);
+    }
+}
+
+/// Where each stage cuts, scales and pads for one capture and display size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    /// Removed from the captured frame to leave the region of interest
+    roi: Edges,
+    /// Size the region is scaled to
+    scaled: (u32, u32),
+    /// `videobox` edges: positive crops the scaled frame, negative adds bars
+    borders: Edges,
+}
+
+fn layout(capture: (u32, u32), display: &DisplayConfig) -> Layout {
+    let (capture_width, capture_height) = capture;
+    let roi = display.roi.unwrap_or(Roi {
+        x: 0,
+        y: 0,
+        width: capture_width,
+        height: capture_height,
+    });
+    let roi_edges = Edges {
+        left: roi.x as i32,
+        right: capture_width.saturating_sub(roi.x + roi.width) as i32,
+        top: roi.y as i32,
+        bottom: capture_height.saturating_sub(roi.y + roi.height) as i32,
+    };
+
+    let (source_width, source_height) = (roi.width.max(1), roi.height.max(1));
+    let (display_width, display_height) = (display.width, display.height);
+    let factor = |select: fn(f64, f64) -> f64| {
+        select(
+            display_width as f64 / source_width as f64,
+            display_height as f64 / source_height as f64,
+        )
+    };
+
+    let scaled = match display.scaling {
+        ScalingMode::Stretch => (display_width, display_height),
+        ScalingMode::Fit => {
+            let factor = factor(f64::min);
+            (
+                even_at_most(source_width as f64 * factor, display_width),
+                even_at_most(source_height as f64 * factor, display_height),
+            )
+        }
+        ScalingMode::Fill => {
+            let factor = factor(f64::max);
+            (
+                even_at_least(source_width as f64 * factor, display_width),
+                even_at_least(source_height as f64 * factor, display_height),
+            )
+        }
+        ScalingMode::Integer => {
+            let multiple = (display_width / source_width).min(display_height / source_height);
+            if multiple >= 1 {
+                (source_width * multiple, source_height * multiple)
+            } else {
+                let divisor = source_width
+                    .div_ceil(display_width.max(1))
+                    .max(source_height.div_ceil(display_height.max(1)));
+                (
+                    (source_width / divisor).max(1),
+                    (source_height / divisor).max(1),
+                )
+            }
+        }
+    };
+
+    Layout {
+        roi: roi_edges,
+        scaled,
+        // Bars (negative) when smaller than the display, a centre crop when larger
+        borders: Edges::centred(
+            scaled.0 as i32 - display_width as i32,
+            scaled.1 as i32 - display_height as i32,
+        ),
+    }
+}
+
+/// Round down to an even size, which every YUV format can scale to
+fn even_at_most(size: f64, limit: u32) -> u32 {
+    ((size as u32) & !1).clamp(2.min(limit), limit)
+}
+
+fn even_at_least(size: f64, limit: u32) -> u32 {
+    ((size.ceil() as u32 + 1) & !1).max(limit)
+}
+
+/// Width and height of raw video caps
+fn frame_size(caps: &gst::CapsRef) -> Option<(u32, u32)> {
+    let structure = caps.structure(0)?;
+    let width = structure.get::<i32>("width").ok()?;
+    let height = structure.get::<i32>("height").ok()?;
+    Some((width.try_into().ok()?, height.try_into().ok()?))
+}
+
+/// The stages between conversion and the sink that shape the frame
+#[derive(Clone)]
+pub struct ScalingStages {
+    /// `videocrop` cutting out `display.roi`
+    pub crop: gst::Element,
+    pub scaler: gst::Element,
+    /// Capsfilter holding the scaled size
+    pub caps: gst::Element,
+    /// `videobox` adding bars or centre-cropping to the display size
+    pub letterbox: gst::Element,
+    /// Frame size the layout is computed from: the configured capture size
+    /// until the negotiated caps reach the crop
+    capture: Arc<Mutex<(u32, u32)>>,
+}
+
+impl ScalingStages {
+    /// Add crop ! scale ! caps ! box and lay them out for `display`
+    pub(super) fn add(
+        builder: &mut PipelineBuilder,
+        names: [&str; 4],
+        capture: (u32, u32),
+        display: &DisplayConfig,
+    ) -> Result<Self, BuildError> {
+        let [crop, scaler, caps, letterbox] = names;
+        let stages = Self {
+            crop: builder.stage("videocrop", crop)?,
+            scaler: builder.stage("videoscale", scaler)?,
+            caps: builder.stage("capsfilter", caps)?,
+            letterbox: builder.stage("videobox", letterbox)?,
+            capture: Arc::new(Mutex::new(capture)),
+        };
+        // Aspect ratio is handled by the layout, not by padding inside the scaler
+        stages.scaler.set_property("add-borders", false);
+        stages.letterbox.set_property_from_str("fill", "black");
+        stages.apply(display);
+        stages.follow_negotiated_size();
+        Ok(stages)
+    }
+
+    /// Re-lay out whenever the caps reaching the crop carry a new frame size
+    ///
+    /// Streams, files and substituted modes rarely deliver exactly the
+    /// configured size, and the aspect ratio and ROI bounds depend on it.
+    fn follow_negotiated_size(&self) {
+        let Some(pad) = self.crop.static_pad("sink") else {
+            return;
+        };
+        // Weak references, as the crop owns the pad holding this probe
+        let elements = [&self.crop, &self.scaler, &self.caps, &self.letterbox]
+            .map(|element| element.downgrade());
+        let capture = self.capture.clone();
+        pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            let Some(gst::PadProbeData::Event(event)) = &info.data else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let gst::EventView::Caps(caps) = event.view() else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let Some(size) = frame_size(caps.caps()) else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let [crop, scaler, caps, letterbox] = &elements;
+            let (Some(crop), Some(scaler), Some(caps), Some(letterbox)) = (
+                crop.upgrade(),
+                scaler.upgrade(),
+                caps.upgrade(),
+                letterbox.upgrade(),
+            ) else {
+                return gst::PadProbeReturn::Remove;
+            };
+            let stages = ScalingStages {
+                crop,
+                scaler,
+                caps,
+                letterbox,
+                capture: capture.clone(),
+            };
+            stages.resize(size);
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    fn resize(&self, size: (u32, u32)) {
+        let previous = std::mem::replace(&mut *self.capture.lock().unwrap(), size);
+        if previous != size {
+            info!(
+                "Frames are {}x{}, not {}x{}; laying out again",
+                size.0, size.1, previous.0, previous.1
+            );
+            self.apply(&crate::CONFIG.load().display);
+        }
+    }
+
+    /// Re-lay out the running stages, e.g. after a reload changed the display
+    ///
+    /// An ROI that does not fit the frame is ignored, showing the whole frame.
+    pub fn apply(&self, display: &DisplayConfig) {
+        let capture = *self.capture.lock().unwrap();
+        let mut display = display.clone();
+        if let Some(roi) = display.roi.filter(|roi| !roi.fits(capture.0, capture.1)) {
+            warn!(
+                "display.roi {}x{} at ({}, {}) does not fit the {}x{} frame; showing the whole frame",
+                roi.width, roi.height, roi.x, roi.y, capture.0, capture.1
+            );
+            display.roi = None;
+        }
+        let display = &display;
+
+        let layout = layout(capture, display);
+        let (mode, width, height) = (display.scaling, display.width, display.height);
+        info!(
+            "{:?} scaling to {}x{} on a {}x{} display",
+            mode, layout.scaled.0, layout.scaled.1, width, height
+        );
+
+        layout.roi.set_on(&self.crop);
+        self.scaler.set_property_from_str(
+            "method",
+            if display.scaling == ScalingMode::Integer {
+                "nearest-neighbour"
+            } else {
+                "bilinear"
+            },
+        );
+        self.caps.set_property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", layout.scaled.0 as i32)
+                .field("height", layout.scaled.1 as i32)
+                .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+                .build(),
+        );
+        layout.borders.set_on(&self.letterbox);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(width: u32, height: u32, scaling: ScalingMode) -> DisplayConfig {
+        DisplayConfig {
+            width,
+            height,
+            scaling,
+            roi: None,
+            ..crate::Config::default().display
+        }
+    }
+
+    fn edges(left: i32, right: i32, top: i32, bottom: i32) -> Edges {
+        Edges {
+            left,
+            right,
+            top,
+            bottom,
+        }
+    }
+
+    #[test]
+    fn reads_the_frame_size_from_caps() {
+        gst::init().unwrap();
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("format", "I420")
+            .field("width", 1280i32)
+            .field("height", 720i32)
+            .build();
+        assert_eq!(frame_size(&caps), Some((1280, 720)));
+        assert_eq!(
+            frame_size(&gst::Caps::new_empty_simple("video/x-raw")),
+            None
+        );
+    }
+
+    #[test]
+    fn fit_pillarboxes_a_narrower_frame() {
+        let layout = layout((640, 480), &display(1280, 720, ScalingMode::Fit));
+        assert_eq!(layout.roi, Edges::default());
+        assert_eq!(layout.scaled, (960, 720));
+        assert_eq!(layout.borders, edges(-160, -160, 0, 0));
+    }
+
+    #[test]
+    fn fit_rounds_down_to_even_sizes_on_an_odd_display() {
+        let layout = layout((1280, 720), &display(1001, 1001, ScalingMode::Fit));
+        // 1001x563.06 rounds down to even sizes, leaving one odd bar pixel
+        assert_eq!(layout.scaled, (1000, 562));
+        assert_eq!(layout.borders, edges(0, -1, -219, -220));
+    }
+
+    #[test]
+    fn fit_scales_an_odd_roi() {
+        let mut display = display(640, 480, ScalingMode::Fit);
+        display.roi = Some(Roi {
+            x: 1,
+            y: 1,
+            width: 101,
+            height: 51,
+        });
+        let layout = layout((640, 480), &display);
+        assert_eq!(layout.roi, edges(1, 538, 1, 428));
+        assert_eq!(layout.scaled, (640, 322));
+        assert_eq!(layout.borders, edges(0, 0, -79, -79));
+    }
+
+    #[test]
+    fn fill_crops_the_overflowing_edges() {
+        let layout = layout((640, 480), &display(1280, 720, ScalingMode::Fill));
+        assert_eq!(layout.scaled, (1280, 960));
+        assert_eq!(layout.borders, edges(0, 0, 120, 120));
+    }
+
+    #[test]
+    fn stretch_fills_the_display() {
+        let layout = layout((640, 480), &display(1280, 720, ScalingMode::Stretch));
+        assert_eq!(layout.scaled, (1280, 720));
+        assert_eq!(layout.borders, Edges::default());
+    }
+
+    #[test]
+    fn integer_uses_the_largest_whole_multiple() {
+        let layout = layout((640, 480), &display(1920, 1080, ScalingMode::Integer));
+        assert_eq!(layout.scaled, (1280, 960));
+        assert_eq!(layout.borders, edges(-320, -320, -60, -60));
+    }
+
+    #[test]
+    fn integer_splits_odd_bars_unevenly() {
+        let layout = layout((641, 481), &display(1280, 720, ScalingMode::Integer));
+        assert_eq!(layout.scaled, (641, 481));
+        assert_eq!(layout.borders, edges(-319, -320, -119, -120));
+    }
+
+    #[test]
+    fn integer_divides_frames_larger_than_the_display() {
+        let layout = layout((1920, 1080), &display(800, 600, ScalingMode::Integer));
+        assert_eq!(layout.scaled, (640, 360));
+        assert_eq!(layout.borders, edges(-80, -80, -120, -120));
+    }
+}
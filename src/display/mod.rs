@@ -1,3 +1,27 @@
+pub mod builder;
+pub mod custom;
+pub mod decoder;
 pub mod display;
+pub mod flight;
+pub mod http;
+pub mod layout;
+mod native;
+mod outputs;
+pub mod overlay;
+pub mod recording;
+#[cfg(feature = "rtsp")]
+pub mod rtsp;
+pub mod sink;
+pub mod supervisor;
 
-pub use display::run_pipeline;
+pub use builder::{BuildError, Chain, PipelineBuilder};
+pub use display::{build_display_pipeline, check_elements, run_pipeline, DisplayPipeline};
+pub use flight::trigger_event;
+pub use http::{check_http, serve_http};
+pub use layout::{Roi, ScalingMode, ScalingStages};
+pub use overlay::OverlayPosition;
+pub use recording::{check_recording, RecordingCodec, RecordingContainer, RecordingMode};
+#[cfg(feature = "rtsp")]
+pub use rtsp::{check_rtsp, client_count};
+pub use sink::VideoSink;
+pub use supervisor::run_supervised;
@@ -0,0 +1,132 @@
+//! Feeds frames from a native [`CaptureSource`](crate::capture::CaptureSource) into the pipeline's `appsrc`
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::{
+    glib::{self, translate::IntoGlib},
+    prelude::*,
+};
+use gstreamer_allocators::DmaBufAllocator;
+use gstreamer_app as gst_app;
+use tracing::debug;
+
+use crate::{
+    capture::{open_source, DmaBuf, Frame},
+    CaptureConfig,
+};
+
+/// Captures on a background thread until dropped
+pub(super) struct Feeder {
+    stop: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Feeder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Open the native source and push its frames into the running pipeline
+///
+/// Capture errors are posted on the pipeline bus so they end the pipeline like
+/// a `v4l2src` failure would.
+pub(super) fn spawn_feeder(source: &gst::Element, capture: &CaptureConfig) -> Result<Feeder> {
+    let appsrc = source
+        .clone()
+        .downcast::<gst_app::AppSrc>()
+        .map_err(|source| eyre!("Pipeline source {} is not an appsrc", source.name()))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let worker = thread::Builder::new().name("capture-feed".into()).spawn({
+        let capture = capture.clone();
+        let stop = stop.clone();
+        move || feed(&appsrc, &capture, &stop)
+    })?;
+
+    Ok(Feeder {
+        stop,
+        worker: Some(worker),
+    })
+}
+
+fn feed(appsrc: &gst_app::AppSrc, config: &CaptureConfig, stop: &AtomicBool) {
+    let mut capture = match open_source(config) {
+        Ok(capture) => capture,
+        Err(e) => {
+            gst::element_error!(appsrc, gst::ResourceError::OpenRead, ("{}", e));
+            return;
+        }
+    };
+
+    let allocator = DmaBufAllocator::new();
+    while !stop.load(Ordering::Relaxed) {
+        let buffer = match capture
+            .next_frame()
+            .and_then(|frame| frame_buffer(&frame, &allocator))
+        {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                gst::element_error!(appsrc, gst::ResourceError::Read, ("{}", e));
+                return;
+            }
+        };
+        if let Err(flow) = appsrc.push_buffer(buffer) {
+            debug!("Capture feed stopped: {:?}", flow);
+            return;
+        }
+    }
+    debug!(
+        "Capture feed stopped after {} dropped frames",
+        capture.dropped_frames()
+    );
+}
+
+/// Wrap a frame for GStreamer, passing DMA-BUF frames by fd without copying
+fn frame_buffer(frame: &Frame, allocator: &DmaBufAllocator) -> Result<gst::Buffer> {
+    let Some(dmabuf) = &frame.dmabuf else {
+        return Ok(gst::Buffer::from_slice(frame.data.clone()));
+    };
+
+    // The memory closes the fd it is given, so it gets a duplicate
+    let fd = dmabuf.fd.try_clone()?;
+    // SAFETY: `fd` is an open DMA-BUF owned by nothing else, and its first `len`
+    // bytes hold the frame
+    let memory = unsafe { allocator.alloc(fd, dmabuf.len as usize)? };
+    hold_until_freed(&memory, dmabuf.clone());
+
+    let mut buffer = gst::Buffer::new();
+    buffer.make_mut().append_memory(memory);
+    Ok(buffer)
+}
+
+/// Keep the driver buffer behind `memory` dequeued until GStreamer frees it
+fn hold_until_freed(memory: &gst::Memory, dmabuf: DmaBuf) {
+    unsafe extern "C" fn release(data: glib::ffi::gpointer) {
+        // SAFETY: `data` is the box leaked below, and GStreamer destroys it once
+        drop(unsafe { Box::from_raw(data as *mut DmaBuf) });
+    }
+
+    let quark = glib::Quark::from_str("apollo-capture-buffer");
+    // SAFETY: `memory` is a live mini object; the qdata owns the leaked box and
+    // hands it to `release` when the memory is freed
+    unsafe {
+        gst::ffi::gst_mini_object_set_qdata(
+            memory.as_mut_ptr() as *mut gst::ffi::GstMiniObject,
+            quark.into_glib(),
+            Box::into_raw(Box::new(dmabuf)) as glib::ffi::gpointer,
+            Some(release),
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! Output branches teed off the display pipeline, attached and detached while it runs
+
+use std::time::Instant;
+
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use tracing::{info, warn};
+
+#[cfg(feature = "rtsp")]
+use super::rtsp::RtspOutput;
+use super::{
+    builder::PipelineBuilder, flight::FlightRecorder, http::MjpegOutput, recording::Recorder,
+};
+#[cfg(feature = "rtsp")]
+use crate::RtspConfig;
+use crate::{GStreamerConfig, HttpConfig, RecordingConfig};
+
+/// How long a stopping branch gets to write its index when the pipeline shuts down
+const FINISH_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(3);
+
+/// Which frames a branch takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Feed {
+    /// Decoded frames, for anything that encodes
+    Decoded,
+    /// The camera's JPEG frames as they arrive
+    Jpeg,
+}
+
+/// An attached branch: its bin and the `tee` pad feeding it
+pub(super) struct Branch {
+    bin: gst::Bin,
+    tee: gst::Element,
+    tee_pad: gst::Pad,
+    started: Instant,
+    /// The stream's own EOS already reached the sink, e.g. at the end of a file source
+    finished: bool,
+}
+
+impl Branch {
+    pub(super) fn started(&self) -> Instant {
+        self.started
+    }
+}
+
+/// One kind of output, fed by at most one branch at a time
+pub(super) trait Output {
+    /// How the output is named in logs
+    const NAME: &'static str;
+
+    fn branch_mut(&mut self) -> Option<&mut Branch>;
+
+    /// Release the output's resources and hand back its branch
+    fn take(&mut self) -> Option<Branch>;
+}
+
+/// The pipeline's `tee`s and the branches still finishing after being detached
+pub(super) struct Tees {
+    pipeline: gst::Pipeline,
+    decoded: gst::Element,
+    jpeg: Option<gst::Element>,
+    /// Detached branches still writing their last segment
+    stopping: Vec<Branch>,
+}
+
+impl Tees {
+    fn new(pipeline: &gst::Pipeline, decoded: gst::Element, jpeg: Option<gst::Element>) -> Self {
+        // Keep display frames flowing while no branch is attached
+        for tee in std::iter::once(&decoded).chain(&jpeg) {
+            tee.set_property("allow-not-linked", true);
+        }
+        Self {
+            pipeline: pipeline.clone(),
+            decoded,
+            jpeg,
+            stopping: Vec::new(),
+        }
+    }
+
+    /// Whether the camera's JPEG frames can be teed off
+    pub(super) fn has_jpeg(&self) -> bool {
+        self.jpeg.is_some()
+    }
+
+    /// Request a pad from the tee for `feed` and start `bin` on it
+    pub(super) fn attach(&self, feed: Feed, bin: gst::Bin) -> Result<Branch> {
+        let tee = match feed {
+            Feed::Decoded => self.decoded.clone(),
+            Feed::Jpeg => self
+                .jpeg
+                .clone()
+                .ok_or_else(|| eyre!("Passthrough needs an MJPEG source"))?,
+        };
+        let sink = bin
+            .static_pad("sink")
+            .ok_or_else(|| eyre!("{} has no sink pad", bin.name()))?;
+
+        self.pipeline.add(&bin)?;
+        let tee_pad = match tee.request_pad_simple("src_%u") {
+            Some(pad) => pad,
+            None => {
+                self.pipeline.remove(&bin).ok();
+                return Err(eyre!("{} refused a new src pad", tee.name()));
+            }
+        };
+        // Playing before linking, so the first frame isn't refused by a stopped bin
+        if let Err(e) = bin
+            .sync_state_with_parent()
+            .map_err(|e| eyre!("{} did not start: {}", bin.name(), e))
+            .and_then(|()| {
+                tee_pad
+                    .link(&sink)
+                    .map(|_| ())
+                    .map_err(|e| eyre!("{:?}", e))
+            })
+        {
+            bin.set_state(gst::State::Null).ok();
+            self.pipeline.remove(&bin).ok();
+            tee.release_request_pad(&tee_pad);
+            return Err(e);
+        }
+
+        Ok(Branch {
+            bin,
+            tee,
+            tee_pad,
+            started: Instant::now(),
+            finished: false,
+        })
+    }
+
+    /// Stop `output`, letting its branch finish what it is writing
+    fn stop<O: Output>(&mut self, output: &mut O) {
+        if let Some(branch) = output.take() {
+            self.detach(branch);
+        }
+    }
+
+    /// Unlink `branch` and send it EOS; it is removed once the EOS arrives
+    fn detach(&mut self, branch: Branch) {
+        // Nothing left to write, and no second EOS would be forwarded
+        if branch.finished {
+            self.remove(branch);
+            return;
+        }
+        let sink = branch.bin.static_pad("sink");
+        branch
+            .tee_pad
+            .add_probe(gst::PadProbeType::IDLE, move |pad, _| {
+                if let Some(sink) = &sink {
+                    pad.unlink(sink).ok();
+                    sink.send_event(gst::event::Eos::new());
+                }
+                gst::PadProbeReturn::Remove
+            });
+        self.stopping.push(branch);
+    }
+
+    /// Drop `output`'s branch at once if `src` is part of it
+    fn fail<O: Output>(
+        &mut self,
+        output: &mut O,
+        src: &gst::Object,
+        err: &gst::message::Error,
+    ) -> bool {
+        if !output
+            .branch_mut()
+            .is_some_and(|branch| src.has_as_ancestor(&branch.bin))
+        {
+            return false;
+        }
+        warn!(
+            "{} failed, display continues: {} ({})",
+            O::NAME,
+            err.error(),
+            err.debug().unwrap_or_default()
+        );
+        if let Some(branch) = output.take() {
+            self.remove(branch);
+        }
+        true
+    }
+
+    fn remove(&self, branch: Branch) {
+        branch.bin.set_state(gst::State::Null).ok();
+        self.pipeline.remove(&branch.bin).ok();
+        branch.tee.release_request_pad(&branch.tee_pad);
+    }
+}
+
+/// Every output a running pipeline can feed
+pub(super) struct Outputs {
+    tees: Tees,
+    recorder: Recorder,
+    flight: FlightRecorder,
+    #[cfg(feature = "rtsp")]
+    rtsp: RtspOutput,
+    mjpeg: MjpegOutput,
+}
+
+impl Outputs {
+    pub(super) fn new(
+        pipeline: &gst::Pipeline,
+        tee: gst::Element,
+        jpeg_tee: Option<gst::Element>,
+    ) -> Self {
+        Self {
+            tees: Tees::new(pipeline, tee, jpeg_tee),
+            recorder: Recorder::default(),
+            flight: FlightRecorder::default(),
+            #[cfg(feature = "rtsp")]
+            rtsp: RtspOutput::default(),
+            mjpeg: MjpegOutput::default(),
+        }
+    }
+
+    pub(super) fn start_recording(
+        &mut self,
+        recording: &RecordingConfig,
+        gstreamer: &GStreamerConfig,
+    ) -> Result<()> {
+        self.recorder.start(&self.tees, recording, gstreamer)
+    }
+
+    /// Detach the recording and let it finish its last segment
+    ///
+    /// The branch is removed once its EOS has reached the muxer, see
+    /// [`Outputs::handle_message`].
+    pub(super) fn stop_recording(&mut self) {
+        self.tees.stop(&mut self.recorder);
+    }
+
+    pub(super) fn arm(
+        &mut self,
+        recording: &RecordingConfig,
+        gstreamer: &GStreamerConfig,
+    ) -> Result<()> {
+        self.flight.arm(&self.tees, recording, gstreamer)
+    }
+
+    /// Stop the flight recorder, saving any event in progress
+    pub(super) fn disarm(&mut self) {
+        self.tees.stop(&mut self.flight);
+    }
+
+    #[cfg(feature = "rtsp")]
+    pub(super) fn serve(&mut self, rtsp: &RtspConfig, gstreamer: &GStreamerConfig) -> Result<()> {
+        self.rtsp.serve(&self.tees, rtsp, gstreamer)
+    }
+
+    /// Stop the RTSP server, disconnecting its clients
+    #[cfg(feature = "rtsp")]
+    pub(super) fn stop_serving(&mut self) {
+        self.tees.stop(&mut self.rtsp);
+    }
+
+    pub(super) fn publish_mjpeg(&mut self, http: &HttpConfig) -> Result<()> {
+        self.mjpeg.publish(&self.tees, http)
+    }
+
+    /// Save an event for each trigger and finish saved events
+    pub(super) fn poll(&mut self) {
+        self.flight.poll();
+    }
+
+    /// Finish stopped branches and contain output errors
+    ///
+    /// Returns true when `msg` came from an output branch and needs no
+    /// further handling.
+    pub(super) fn handle_message(&mut self, msg: &gst::Message) -> bool {
+        let Some(src) = msg.src() else {
+            return false;
+        };
+
+        match msg.view() {
+            // Branch bins forward their EOS, posted once the muxer has finished
+            gst::MessageView::Element(element) if is_forwarded_eos(element) => {
+                if let Some(index) = self
+                    .tees
+                    .stopping
+                    .iter()
+                    .position(|b| b.bin.upcast_ref::<gst::Object>() == src)
+                {
+                    let branch = self.tees.stopping.remove(index);
+                    self.tees.remove(branch);
+                    info!("Recording finished");
+                } else if let Some(branch) = self.active_branch(src) {
+                    branch.finished = true;
+                }
+                true
+            }
+            gst::MessageView::Error(err) => {
+                #[cfg(feature = "rtsp")]
+                if self.tees.fail(&mut self.rtsp, src, err) {
+                    return true;
+                }
+                if self.tees.fail(&mut self.recorder, src, err)
+                    || self.tees.fail(&mut self.flight, src, err)
+                    || self.tees.fail(&mut self.mjpeg, src, err)
+                {
+                    return true;
+                }
+                let in_branch = |branch: &Branch| src.has_as_ancestor(&branch.bin);
+                if let Some(index) = self.tees.stopping.iter().position(in_branch) {
+                    warn!("Recording failed while finishing: {}", err.error());
+                    let branch = self.tees.stopping.remove(index);
+                    self.tees.remove(branch);
+                    return true;
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Stop every output and wait for every stopped branch to finish writing
+    ///
+    /// Called before the pipeline stops, which would otherwise cut off the
+    /// last segment (and its index for MP4).
+    pub(super) fn finish(&mut self, bus: &gst::Bus) {
+        self.stop_recording();
+        self.disarm();
+        #[cfg(feature = "rtsp")]
+        self.stop_serving();
+        self.tees.stop(&mut self.mjpeg);
+        let deadline = Instant::now() + std::time::Duration::from(FINISH_TIMEOUT);
+        while !self.tees.stopping.is_empty() {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                warn!("Recording did not finish in time; the last segment may be truncated");
+                break;
+            };
+            let timeout = gst::ClockTime::from_nseconds(remaining.as_nanos() as u64);
+            match bus.timed_pop(timeout) {
+                Some(msg) => {
+                    self.handle_message(&msg);
+                }
+                None => continue,
+            }
+        }
+    }
+
+    /// The attached branch whose bin is `src`
+    fn active_branch(&mut self, src: &gst::Object) -> Option<&mut Branch> {
+        let flight = self.flight.branch_mut();
+        #[cfg(feature = "rtsp")]
+        let flight = flight.or(self.rtsp.branch_mut());
+        self.recorder
+            .branch_mut()
+            .into_iter()
+            .chain(flight)
+            .chain(self.mjpeg.branch_mut())
+            .find(|branch| branch.bin.upcast_ref::<gst::Object>() == src)
+    }
+}
+
+fn is_forwarded_eos(element: &gst::message::Element) -> bool {
+    element.structure().is_some_and(|s| {
+        s.name() == "GstBinForwarded"
+            && s.get::<gst::Message>("message")
+                .is_ok_and(|inner| inner.type_() == gst::MessageType::Eos)
+    })
+}
+
+/// Wrap the built stages in a bin with a ghost sink pad
+pub(super) fn branch_bin(builder: PipelineBuilder) -> Result<gst::Bin> {
+    let (bin, first) = builder.finish_bin();
+    let target = first
+        .and_then(|queue| queue.static_pad("sink"))
+        .expect("queue has a sink pad");
+    bin.add_pad(&gst::GhostPad::with_target(&target)?)?;
+    // Surfaces the sink's EOS, which the pipeline would otherwise hold back
+    bin.set_property("message-forward", true);
+    Ok(bin)
+}
@@ -0,0 +1,199 @@
+//! On-screen pipeline statistics drawn with `textoverlay`
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::builder::{BuildError, PipelineBuilder};
+use crate::{GStreamerConfig, Metrics};
+
+/// How often the overlay text is recomputed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latency samples kept per refresh; enough for 240 fps
+const MAX_LATENCY_SAMPLES: usize = 256;
+
+/// Corner of the frame the statistics are drawn in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    #[default]
+    BottomLeft,
+    BottomRight,
+}
+
+/// Add the statistics `textoverlay` stage, silent unless enabled
+pub(super) fn add_stats_overlay(
+    builder: &mut PipelineBuilder,
+    name: &str,
+    gstreamer: &GStreamerConfig,
+) -> Result<gst::Element, BuildError> {
+    let overlay = builder.stage("textoverlay", name)?;
+    overlay.set_property_from_str("line-alignment", "left");
+    overlay.set_property("shaded-background", true);
+    configure(&overlay, gstreamer);
+    Ok(overlay)
+}
+
+/// Apply the overlay switch, position and font size
+pub(super) fn configure(overlay: &gst::Element, gstreamer: &GStreamerConfig) {
+    let (valignment, halignment) = match gstreamer.overlay_position {
+        OverlayPosition::TopLeft => ("top", "left"),
+        OverlayPosition::TopRight => ("top", "right"),
+        OverlayPosition::BottomLeft => ("bottom", "left"),
+        OverlayPosition::BottomRight => ("bottom", "right"),
+    };
+    overlay.set_property("silent", !gstreamer.stats_overlay);
+    overlay.set_property_from_str("valignment", valignment);
+    overlay.set_property_from_str("halignment", halignment);
+    overlay.set_property(
+        "font-desc",
+        format!("Monospace {}", gstreamer.overlay_font_size),
+    );
+}
+
+/// Frames counted since the last refresh
+#[derive(Default)]
+struct Window {
+    captured: u64,
+    displayed: u64,
+    latencies_ms: Vec<f64>,
+}
+
+/// Measures the running pipeline and keeps the overlay text current
+pub(super) struct StatsOverlay {
+    overlay: gst::Element,
+    sink: gst::Element,
+    decoder: String,
+    window: Arc<Mutex<Window>>,
+    started: Instant,
+}
+
+impl StatsOverlay {
+    /// Count frames entering at `captured` and leaving through `sink`
+    ///
+    /// Latency is the sink's running time minus the buffer timestamp, i.e. the
+    /// time since capture for live sources.
+    pub(super) fn attach(
+        overlay: gst::Element,
+        captured: &gst::Pad,
+        sink: gst::Element,
+        decoder: Option<&gst::Element>,
+    ) -> Self {
+        let window = Arc::new(Mutex::new(Window::default()));
+
+        captured.add_probe(gst::PadProbeType::BUFFER, {
+            let window = window.clone();
+            move |_, _| {
+                window.lock().unwrap().captured += 1;
+                gst::PadProbeReturn::Ok
+            }
+        });
+
+        if let Some(pad) = sink.static_pad("sink") {
+            let window = window.clone();
+            let element = sink.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                let pts = info.buffer().and_then(|buffer| buffer.pts());
+                let now = element.current_running_time();
+                let mut window = window.lock().unwrap();
+                window.displayed += 1;
+                if let (Some(pts), Some(now)) = (pts, now) {
+                    if window.latencies_ms.len() < MAX_LATENCY_SAMPLES {
+                        let latency = now.saturating_sub(pts);
+                        window.latencies_ms.push(latency.nseconds() as f64 / 1e6);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+
+        let decoder = decoder.and_then(|decoder| decoder.factory()).map_or_else(
+            || "none (raw)".to_string(),
+            |factory| factory.name().to_string(),
+        );
+
+        Self {
+            overlay,
+            sink,
+            decoder,
+            window,
+            started: Instant::now(),
+        }
+    }
+
+    pub(super) fn configure(&self, gstreamer: &GStreamerConfig) {
+        configure(&self.overlay, gstreamer);
+    }
+
+    /// Recompute the metrics once per refresh interval and redraw the text
+    pub(super) fn refresh(&mut self) {
+        let elapsed = self.started.elapsed();
+        if elapsed < REFRESH_INTERVAL {
+            return;
+        }
+        self.started = Instant::now();
+
+        let window = std::mem::take(&mut *self.window.lock().unwrap());
+        let metrics = metrics(window, elapsed, self.sink.property::<u64>("frames-dropped"));
+        self.overlay
+            .set_property("text", overlay_text(&metrics, &self.decoder));
+    }
+}
+
+fn metrics(mut window: Window, elapsed: Duration, dropped_frames: u64) -> Metrics {
+    let seconds = elapsed.as_secs_f64();
+    window.latencies_ms.sort_by(f64::total_cmp);
+    let samples = &window.latencies_ms;
+    let (avg_latency_ms, p99_latency_ms) = match samples.len() {
+        0 => (0.0, 0.0),
+        count => (
+            samples.iter().sum::<f64>() / count as f64,
+            samples[(count * 99 / 100).min(count - 1)],
+        ),
+    };
+
+    Metrics {
+        capture_fps: window.captured as f64 / seconds,
+        display_fps: window.displayed as f64 / seconds,
+        dropped_frames,
+        avg_latency_ms,
+        p99_latency_ms,
+        http_clients: super::http::client_count(),
+        #[cfg(feature = "rtsp")]
+        rtsp_clients: super::rtsp::client_count(),
+        ..Metrics::default()
+    }
+}
+
+fn overlay_text(metrics: &Metrics, decoder: &str) -> String {
+    let mut text = format!(
+        "capture  {:.1} fps\n\
+         display  {:.1} fps\n\
+         dropped  {}\n\
+         decoder  {}\n\
+         latency  {:.1} ms (p99 {:.1} ms)",
+        metrics.capture_fps,
+        metrics.display_fps,
+        metrics.dropped_frames,
+        decoder,
+        metrics.avg_latency_ms,
+        metrics.p99_latency_ms
+    );
+    // Viewer counts only for the outputs in use
+    let config = crate::CONFIG.load();
+    if config.http.enabled {
+        text += &format!("\nhttp     {} clients", metrics.http_clients);
+    }
+    #[cfg(feature = "rtsp")]
+    if config.rtsp.enabled {
+        text += &format!("\nrtsp     {} clients", metrics.rtsp_clients);
+    }
+    text
+}
@@ -0,0 +1,492 @@
+//! Recording branch teed off the display pipeline, started and stopped while it runs
+
+use std::{fs, path::Path};
+
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{
+    builder::PipelineBuilder,
+    decoder::candidates,
+    display::find_missing,
+    outputs::{branch_bin, Branch, Feed, Output, Tees},
+};
+use crate::{GStreamerConfig, RecordingConfig};
+
+/// H.264 encoders, hardware first
+pub const H264_ENCODERS: [&str; 5] = [
+    "nvh264enc",
+    "vah264enc",
+    "vaapih264enc",
+    "v4l2h264enc",
+    "x264enc",
+];
+
+/// Always tried after the H.264 encoders
+const H264_SOFTWARE_ENCODER: &str = "openh264enc";
+
+/// H.265 encoders, hardware first
+pub const H265_ENCODERS: [&str; 4] = ["nvh265enc", "vah265enc", "vaapih265enc", "v4l2h265enc"];
+
+/// Always tried after the H.265 encoders
+const H265_SOFTWARE_ENCODER: &str = "x265enc";
+
+/// Size of the frame encoded by the preroll check; large enough for hardware minimums
+const TEST_FRAME_SIZE: (u32, u32) = (320, 240);
+
+/// How long a candidate gets to encode the test frame
+const PREROLL_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(2);
+
+/// What a recording stores
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordingMode {
+    /// Re-encode the decoded frames with `recording.codec`
+    #[default]
+    Encode,
+    /// Store the camera's JPEG frames as they arrive: no encoding, full quality
+    Passthrough,
+}
+
+/// Video codec of encoded recordings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordingCodec {
+    #[default]
+    H264,
+    H265,
+}
+
+impl RecordingCodec {
+    fn parse(self) -> &'static str {
+        match self {
+            RecordingCodec::H264 => "h264parse",
+            RecordingCodec::H265 => "h265parse",
+        }
+    }
+
+    fn software_encoder(self) -> &'static str {
+        match self {
+            RecordingCodec::H264 => H264_SOFTWARE_ENCODER,
+            RecordingCodec::H265 => H265_SOFTWARE_ENCODER,
+        }
+    }
+}
+
+/// File format of recorded segments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordingContainer {
+    Mp4,
+    /// Stays playable if Apollo is killed mid-segment
+    #[default]
+    Mkv,
+    Avi,
+}
+
+impl RecordingContainer {
+    pub(super) fn muxer(self) -> &'static str {
+        match self {
+            RecordingContainer::Mp4 => "mp4mux",
+            RecordingContainer::Mkv => "matroskamux",
+            RecordingContainer::Avi => "avimux",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            RecordingContainer::Mp4 => "mp4",
+            RecordingContainer::Mkv => "mkv",
+            RecordingContainer::Avi => "avi",
+        }
+    }
+}
+
+/// Caps the muxer accepts for a branch's output: AVI takes H.264/H.265 as a byte stream
+pub(super) fn muxable_caps(recording: &RecordingConfig) -> gst::Caps {
+    let (media_type, packetized) = match (recording.mode, recording.codec) {
+        (RecordingMode::Passthrough, _) => return gst::Caps::new_empty_simple("image/jpeg"),
+        (RecordingMode::Encode, RecordingCodec::H264) => ("video/x-h264", "avc"),
+        (RecordingMode::Encode, RecordingCodec::H265) => ("video/x-h265", "hvc1"),
+    };
+    let stream_format = match recording.container {
+        RecordingContainer::Avi => "byte-stream",
+        _ => packetized,
+    };
+    gst::Caps::builder(media_type)
+        .field("stream-format", stream_format)
+        .field("alignment", "au")
+        .build()
+}
+
+/// Installed encoders for `codec` in preference order
+pub fn encoder_candidates(codec: RecordingCodec, gstreamer: &GStreamerConfig) -> Vec<&'static str> {
+    let preferred: &[&'static str] = match codec {
+        RecordingCodec::H264 => &H264_ENCODERS,
+        RecordingCodec::H265 => &H265_ENCODERS,
+    };
+    candidates(preferred, codec.software_encoder(), gstreamer)
+}
+
+/// The first usable encoder for `codec`
+///
+/// Each installed candidate must encode a test frame before it is chosen, which
+/// weeds out elements whose device is missing (e.g. `vah264enc` without a VA
+/// driver). The software encoder is the last resort.
+fn select_encoder(
+    codec: RecordingCodec,
+    bitrate_kbps: u32,
+    gstreamer: &GStreamerConfig,
+) -> Result<&'static str> {
+    let candidates = encoder_candidates(codec, gstreamer);
+    for &encoder in &candidates {
+        match preroll_test_frame(encoder, bitrate_kbps) {
+            Ok(()) => return Ok(encoder),
+            Err(e) => warn!("Encoder {} failed the preroll check: {}", encoder, e),
+        }
+    }
+
+    let software = codec.software_encoder();
+    if !candidates.contains(&software) {
+        return Err(eyre!("No {:?} encoder is installed", codec));
+    }
+    warn!(
+        "No {:?} encoder passed the preroll check; using {}",
+        codec, software
+    );
+    Ok(software)
+}
+
+/// Encode one generated frame through `encoder` into a fakesink
+fn preroll_test_frame(encoder: &str, bitrate_kbps: u32) -> Result<()> {
+    let (width, height) = TEST_FRAME_SIZE;
+    let mut builder = PipelineBuilder::new("encodercheck");
+    builder
+        .stage("videotestsrc", "source")?
+        .set_property("num-buffers", 1i32);
+    builder.caps(
+        "size",
+        &gst::Caps::builder("video/x-raw")
+            .field("width", width as i32)
+            .field("height", height as i32)
+            .build(),
+    )?;
+    builder.stage("videoconvert", "convert")?;
+    let element = builder.stage(encoder, "encoder")?;
+    configure_encoder(&element, encoder, bitrate_kbps);
+    builder.stage("fakesink", "sink")?;
+    let (pipeline, _) = builder.finish();
+
+    let result = preroll(&pipeline);
+    pipeline.set_state(gst::State::Null).ok();
+    result
+}
+
+fn preroll(pipeline: &gst::Pipeline) -> Result<()> {
+    pipeline
+        .set_state(gst::State::Paused)
+        .map_err(|_| eyre!("failed to start"))?;
+
+    // Prerolling completes once the encoded frame reaches the sink
+    let bus = pipeline.bus().ok_or_else(|| eyre!("pipeline has no bus"))?;
+    let msg = bus
+        .timed_pop_filtered(
+            PREROLL_TIMEOUT,
+            &[gst::MessageType::AsyncDone, gst::MessageType::Error],
+        )
+        .ok_or_else(|| eyre!("timed out encoding a test frame"))?;
+    match msg.view() {
+        gst::MessageView::Error(err) => Err(eyre!("{}", err.error())),
+        _ => Ok(()),
+    }
+}
+
+/// Check that the configured recording can be built
+pub fn check_recording(recording: &RecordingConfig, gstreamer: &GStreamerConfig) -> Result<()> {
+    if recording.flight_recorder {
+        find_missing(vec!["appsink", "appsrc", "filesink"])?;
+    }
+    if recording.mode == RecordingMode::Passthrough {
+        return find_missing(vec![
+            "queue",
+            "jpegparse",
+            "splitmuxsink",
+            recording.container.muxer(),
+        ]);
+    }
+
+    find_missing(vec![
+        "queue",
+        "videoconvert",
+        recording.codec.parse(),
+        "splitmuxsink",
+        recording.container.muxer(),
+    ])?;
+    if encoder_candidates(recording.codec, gstreamer).is_empty() {
+        return Err(eyre!("No {:?} encoder is installed", recording.codec));
+    }
+    Ok(())
+}
+
+impl From<RecordingMode> for Feed {
+    fn from(mode: RecordingMode) -> Self {
+        match mode {
+            RecordingMode::Encode => Feed::Decoded,
+            RecordingMode::Passthrough => Feed::Jpeg,
+        }
+    }
+}
+
+/// Records segments into `recording.output_dir` while enabled
+#[derive(Default)]
+pub(super) struct Recorder {
+    branch: Option<Branch>,
+}
+
+impl Recorder {
+    /// Start a new recording into `recording.output_dir`
+    pub(super) fn start(
+        &mut self,
+        tees: &Tees,
+        recording: &RecordingConfig,
+        gstreamer: &GStreamerConfig,
+    ) -> Result<()> {
+        if self.branch.is_some() {
+            return Ok(());
+        }
+
+        create_output_dir(recording)?;
+        let mut builder = PipelineBuilder::bin("recording");
+        let description = add_encoding(
+            &mut builder,
+            "record",
+            recording.mode,
+            recording.codec,
+            recording.bitrate_kbps,
+            gstreamer,
+        )?;
+        let sink = builder.stage("splitmuxsink", "recordsink")?;
+        sink.set_property("muxer-factory", recording.container.muxer());
+        sink.set_property("location", output_path(recording, "apollo", "-%05d")?);
+        if recording.segment_seconds > 0 {
+            sink.set_property(
+                "max-size-time",
+                gst::ClockTime::from_seconds(recording.segment_seconds.into()).nseconds(),
+            );
+            // Every JPEG is a keyframe; encoders are asked for one at each split
+            sink.set_property(
+                "send-keyframe-requests",
+                recording.mode == RecordingMode::Encode,
+            );
+        }
+        let bin = branch_bin(builder)?;
+        if let Some(sink) = bin.static_pad("sink") {
+            start_at_zero(&sink);
+        }
+
+        self.branch = Some(tees.attach(recording.mode.into(), bin)?);
+        info!("Recording {} to {}", description, recording.output_dir);
+        Ok(())
+    }
+}
+
+impl Output for Recorder {
+    const NAME: &'static str = "Recording";
+
+    fn branch_mut(&mut self) -> Option<&mut Branch> {
+        self.branch.as_mut()
+    }
+
+    fn take(&mut self) -> Option<Branch> {
+        let branch = self.branch.take()?;
+        info!(
+            "Stopping recording after {:.0?}",
+            branch.started().elapsed()
+        );
+        Some(branch)
+    }
+}
+
+/// The start of an output branch for `mode`, returning a description
+///
+/// Encode: queue ! videoconvert ! encoder ! parse. Passthrough: queue ! jpegparse,
+/// which only fills in the caps the muxer needs. Stage names start with `prefix`.
+pub(super) fn add_encoding(
+    builder: &mut PipelineBuilder,
+    prefix: &str,
+    mode: RecordingMode,
+    codec: RecordingCodec,
+    bitrate_kbps: u32,
+    gstreamer: &GStreamerConfig,
+) -> Result<String> {
+    // Drop frames for the recording rather than stall the display
+    let queue = builder.stage("queue", &format!("{}queue", prefix))?;
+    queue.set_property_from_str("leaky", "downstream");
+
+    match mode {
+        RecordingMode::Encode => {
+            let encoder = select_encoder(codec, bitrate_kbps, gstreamer)?;
+            builder.stage("videoconvert", &format!("{}convert", prefix))?;
+            let element = builder.stage(encoder, &format!("{}enc", prefix))?;
+            configure_encoder(&element, encoder, bitrate_kbps);
+            // Repeat SPS/PPS so files cut from the stream decode from their first keyframe
+            builder
+                .stage(codec.parse(), &format!("{}parse", prefix))?
+                .set_property("config-interval", -1i32);
+            Ok(format!(
+                "{:?} with {} at {} kbit/s",
+                codec, encoder, bitrate_kbps
+            ))
+        }
+        RecordingMode::Passthrough => {
+            builder.stage("jpegparse", &format!("{}parse", prefix))?;
+            Ok("camera MJPEG without re-encoding".to_string())
+        }
+    }
+}
+
+pub(super) fn create_output_dir(recording: &RecordingConfig) -> Result<()> {
+    fs::create_dir_all(&recording.output_dir).map_err(|e| {
+        eyre!(
+            "Cannot create recording directory {}: {}",
+            recording.output_dir,
+            e
+        )
+    })
+}
+
+/// Shift the branch's timestamps so a recording started mid-stream begins at zero
+fn start_at_zero(sink: &gst::Pad) {
+    sink.add_probe(gst::PadProbeType::BUFFER, |pad, info| {
+        let Some(pts) = info.buffer().and_then(|buffer| buffer.pts()) else {
+            return gst::PadProbeReturn::Ok;
+        };
+        pad.set_offset(-(pts.nseconds() as i64));
+        gst::PadProbeReturn::Remove
+    });
+}
+
+/// Bitrate in the unit each encoder family expects; live-friendly presets for software
+fn configure_encoder(encoder: &gst::Element, factory: &str, bitrate_kbps: u32) {
+    match factory {
+        "openh264enc" => encoder.set_property("bitrate", bitrate_kbps * 1000),
+        "x264enc" | "x265enc" => {
+            encoder.set_property_from_str("bitrate", &bitrate_kbps.to_string());
+            encoder.set_property_from_str("speed-preset", "superfast");
+            encoder.set_property_from_str("tune", "zerolatency");
+        }
+        name if name.starts_with("v4l2") => {
+            let controls = gst::Structure::builder("controls")
+                .field("video_bitrate", (bitrate_kbps * 1000) as i32)
+                .build();
+            encoder.set_property("extra-controls", controls);
+        }
+        _ if encoder.has_property("bitrate", None) => {
+            encoder.set_property_from_str("bitrate", &bitrate_kbps.to_string());
+        }
+        _ => warn!("Don't know how to set the bitrate of {}", factory),
+    }
+}
+
+/// `<output_dir>/<prefix>-<local time><suffix>.<ext>`
+pub(super) fn output_path(
+    recording: &RecordingConfig,
+    prefix: &str,
+    suffix: &str,
+) -> Result<String> {
+    let now = gst::glib::DateTime::now_local()
+        .and_then(|now| now.format("%Y%m%d-%H%M%S"))
+        .map_err(|e| eyre!("Cannot read the local time: {}", e))?;
+    let file = format!(
+        "{}-{}{}.{}",
+        prefix,
+        now,
+        suffix,
+        recording.container.extension()
+    );
+    Ok(Path::new(&recording.output_dir)
+        .join(file)
+        .to_string_lossy()
+        .into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(
+        mode: RecordingMode,
+        codec: RecordingCodec,
+        container: RecordingContainer,
+    ) -> RecordingConfig {
+        RecordingConfig {
+            mode,
+            codec,
+            container,
+            output_dir: "/var/recordings".into(),
+            ..crate::Config::default().recording
+        }
+    }
+
+    #[test]
+    fn muxable_caps_follow_mode_codec_and_container() {
+        gst::init().unwrap();
+
+        let caps = |mode, codec, container| muxable_caps(&recording(mode, codec, container));
+        assert_eq!(
+            caps(
+                RecordingMode::Passthrough,
+                RecordingCodec::H265,
+                RecordingContainer::Avi
+            ),
+            gst::Caps::new_empty_simple("image/jpeg")
+        );
+        assert_eq!(
+            caps(
+                RecordingMode::Encode,
+                RecordingCodec::H264,
+                RecordingContainer::Mkv
+            )
+            .to_string(),
+            "video/x-h264, stream-format=(string)avc, alignment=(string)au"
+        );
+        assert_eq!(
+            caps(
+                RecordingMode::Encode,
+                RecordingCodec::H265,
+                RecordingContainer::Mp4
+            )
+            .to_string(),
+            "video/x-h265, stream-format=(string)hvc1, alignment=(string)au"
+        );
+        assert_eq!(
+            caps(
+                RecordingMode::Encode,
+                RecordingCodec::H264,
+                RecordingContainer::Avi
+            )
+            .to_string(),
+            "video/x-h264, stream-format=(string)byte-stream, alignment=(string)au"
+        );
+    }
+
+    #[test]
+    fn output_path_is_stamped_inside_the_output_dir() {
+        let config = recording(
+            RecordingMode::Encode,
+            RecordingCodec::H264,
+            RecordingContainer::Mp4,
+        );
+        let path = output_path(&config, "event", "-%05d").unwrap();
+
+        let file = path.strip_prefix("/var/recordings/event-").unwrap();
+        let stamp = file.strip_suffix("-%05d.mp4").unwrap();
+        // %Y%m%d-%H%M%S
+        assert_eq!(stamp.len(), 15);
+        assert_eq!(stamp.as_bytes()[8], b'-');
+        assert!(stamp
+            .bytes()
+            .enumerate()
+            .all(|(i, b)| i == 8 || b.is_ascii_digit()));
+    }
+}
@@ -0,0 +1,298 @@
+//! RTSP server publishing the live feed to other machines
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::{glib, prelude::*};
+use gstreamer_app as gst_app;
+use gstreamer_rtsp_server::{self as gst_rtsp_server, prelude::*};
+use gstreamer_video as gst_video;
+use tracing::{info, warn};
+
+use super::{
+    builder::PipelineBuilder,
+    display::find_missing,
+    outputs::{branch_bin, Branch, Output, Tees},
+    recording::{add_encoding, encoder_candidates, RecordingCodec, RecordingMode},
+};
+use crate::{GStreamerConfig, RtspConfig};
+
+/// Role the factory grants to clients logged in with `rtsp.username`
+const VIEWER_ROLE: &str = "viewer";
+
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// RTSP clients currently connected
+pub fn client_count() -> usize {
+    CLIENTS.load(Ordering::SeqCst)
+}
+
+/// Check that the RTSP output can be built
+pub fn check_rtsp(rtsp: &RtspConfig, gstreamer: &GStreamerConfig) -> Result<()> {
+    find_missing(vec!["queue", "appsink", "appsrc"])?;
+    match rtsp.mode {
+        RecordingMode::Passthrough => find_missing(vec!["jpegparse", "rtpjpegpay"]),
+        RecordingMode::Encode => {
+            find_missing(vec!["videoconvert", "h264parse", "rtph264pay"])?;
+            if encoder_candidates(RecordingCodec::H264, gstreamer).is_empty() {
+                return Err(eyre!("No H264 encoder is installed"));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Caps the branch hands to the server for `mode`
+pub(super) fn stream_caps(mode: RecordingMode) -> gst::Caps {
+    match mode {
+        RecordingMode::Passthrough => gst::Caps::new_empty_simple("image/jpeg"),
+        RecordingMode::Encode => gst::Caps::builder("video/x-h264")
+            .field("stream-format", "byte-stream")
+            .field("alignment", "au")
+            .build(),
+    }
+}
+
+/// Launch line of each client-facing media: the branch's frames, payloaded
+fn media_launch(mode: RecordingMode) -> &'static str {
+    match mode {
+        RecordingMode::Passthrough => {
+            "( appsrc name=src is-live=true format=time do-timestamp=true \
+             ! rtpjpegpay name=pay0 pt=26 )"
+        }
+        RecordingMode::Encode => {
+            "( appsrc name=src is-live=true format=time do-timestamp=true \
+             ! h264parse ! rtph264pay name=pay0 pt=96 config-interval=1 )"
+        }
+    }
+}
+
+/// Streams the feed to RTSP clients while enabled
+#[derive(Default)]
+pub(super) struct RtspOutput {
+    stream: Option<(Branch, RtspServer)>,
+}
+
+impl RtspOutput {
+    /// Publish the feed at `rtsp.mount` on `rtsp.port`
+    pub(super) fn serve(
+        &mut self,
+        tees: &Tees,
+        rtsp: &RtspConfig,
+        gstreamer: &GStreamerConfig,
+    ) -> Result<()> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+
+        let mut builder = PipelineBuilder::bin("rtspoutput");
+        let description = add_encoding(
+            &mut builder,
+            "rtsp",
+            rtsp.mode,
+            RecordingCodec::H264,
+            rtsp.bitrate_kbps,
+            gstreamer,
+        )?;
+        let sink = builder.stage("appsink", "rtspsink")?;
+        let bin = branch_bin(builder)?;
+        let server = RtspServer::start(&sink, rtsp)?;
+
+        let branch = tees.attach(rtsp.mode.into(), bin)?;
+        self.stream = Some((branch, server));
+        info!("Streaming {} over RTSP", description);
+        Ok(())
+    }
+}
+
+impl Output for RtspOutput {
+    const NAME: &'static str = "RTSP output";
+
+    fn branch_mut(&mut self) -> Option<&mut Branch> {
+        self.stream.as_mut().map(|(branch, _)| branch)
+    }
+
+    /// Disconnects every client first
+    fn take(&mut self) -> Option<Branch> {
+        let (branch, server) = self.stream.take()?;
+        drop(server);
+        Some(branch)
+    }
+}
+
+/// The server and the thread running its main loop
+struct RtspServer {
+    server: gst_rtsp_server::RTSPServer,
+    main_loop: glib::MainLoop,
+    source: Option<glib::SourceId>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RtspServer {
+    /// Serve the samples reaching `sink` at `rtsp.mount` on `rtsp.port`
+    ///
+    /// Every client shares one media; frames are dropped while nobody watches.
+    fn start(sink: &gst::Element, rtsp: &RtspConfig) -> Result<Self> {
+        // The appsrc of the media being watched, replaced when a new one is configured
+        let source: Arc<Mutex<Option<gst_app::AppSrc>>> = Arc::new(Mutex::new(None));
+
+        let appsink = sink
+            .clone()
+            .downcast::<gst_app::AppSink>()
+            .map_err(|_| eyre!("{} is not an appsink", sink.name()))?;
+        appsink.set_caps(Some(&stream_caps(rtsp.mode)));
+        appsink.set_property("sync", false);
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample({
+                    let source = source.clone();
+                    move |appsink| {
+                        let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        let mut source = source.lock().unwrap();
+                        if let Some(appsrc) = source.as_ref() {
+                            if forward(appsrc, &sample).is_err() {
+                                // The media was torn down after its last client left
+                                *source = None;
+                            }
+                        }
+                        Ok(gst::FlowSuccess::Ok)
+                    }
+                })
+                .build(),
+        );
+
+        let factory = gst_rtsp_server::RTSPMediaFactory::new();
+        factory.set_launch(media_launch(rtsp.mode));
+        factory.set_shared(true);
+        factory.connect_media_configure({
+            let source = source.clone();
+            let appsink = appsink.clone();
+            let mode = rtsp.mode;
+            move |_, media| {
+                let appsrc = media
+                    .element()
+                    .downcast::<gst::Bin>()
+                    .ok()
+                    .and_then(|bin| bin.by_name("src"))
+                    .and_then(|src| src.downcast::<gst_app::AppSrc>().ok());
+                match appsrc {
+                    Some(appsrc) => *source.lock().unwrap() = Some(appsrc),
+                    None => warn!("RTSP media has no appsrc named src"),
+                }
+                // New viewers can only decode from a keyframe
+                if mode == RecordingMode::Encode {
+                    appsink.send_event(
+                        gst_video::UpstreamForceKeyUnitEvent::builder()
+                            .all_headers(true)
+                            .build(),
+                    );
+                }
+            }
+        });
+
+        let server = gst_rtsp_server::RTSPServer::new();
+        server.set_service(&rtsp.port.to_string());
+        if let (Some(username), Some(password)) = (&rtsp.username, &rtsp.password) {
+            let auth = gst_rtsp_server::RTSPAuth::new();
+            let token = gst_rtsp_server::RTSPToken::builder()
+                .field(gst_rtsp_server::RTSP_TOKEN_MEDIA_FACTORY_ROLE, VIEWER_ROLE)
+                .build();
+            auth.add_basic(
+                &gst_rtsp_server::RTSPAuth::make_basic(username, password),
+                &token,
+            );
+            server.set_auth(Some(&auth));
+            factory.add_role_from_structure(
+                &gst::Structure::builder(VIEWER_ROLE)
+                    .field(gst_rtsp_server::RTSP_PERM_MEDIA_FACTORY_ACCESS, true)
+                    .field(gst_rtsp_server::RTSP_PERM_MEDIA_FACTORY_CONSTRUCT, true)
+                    .build(),
+            );
+        }
+        server
+            .mount_points()
+            .ok_or_else(|| eyre!("RTSP server has no mount points"))?
+            .add_factory(&rtsp.mount, factory);
+
+        server.connect_client_connected(|_, client| {
+            let clients = CLIENTS.fetch_add(1, Ordering::SeqCst) + 1;
+            info!("RTSP client connected ({} watching)", clients);
+            client.connect_closed(|_| {
+                let clients = CLIENTS.fetch_sub(1, Ordering::SeqCst) - 1;
+                info!("RTSP client disconnected ({} watching)", clients);
+            });
+        });
+
+        // The server runs on its own context, away from the display's bus polling
+        let context = glib::MainContext::new();
+        let main_loop = glib::MainLoop::new(Some(&context), false);
+        let id = server
+            .attach(Some(&context))
+            .map_err(|e| eyre!("Cannot listen for RTSP on port {}: {}", rtsp.port, e))?;
+        let thread = thread::Builder::new().name("rtsp".into()).spawn({
+            let main_loop = main_loop.clone();
+            move || {
+                if let Err(e) = context.with_thread_default(|| main_loop.run()) {
+                    warn!("RTSP server stopped: {}", e);
+                }
+            }
+        })?;
+
+        let auth = if rtsp.username.is_some() {
+            "with basic authentication"
+        } else {
+            "without authentication"
+        };
+        info!(
+            "Serving rtsp://0.0.0.0:{}{} {}",
+            rtsp.port, rtsp.mount, auth
+        );
+        Ok(Self {
+            server,
+            main_loop,
+            source: Some(id),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for RtspServer {
+    /// Disconnect every client and stop listening
+    fn drop(&mut self) {
+        self.server
+            .client_filter(Some(&mut |_, _| gst_rtsp_server::RTSPFilterResult::Remove));
+        if let Some(source) = self.source.take() {
+            source.remove();
+        }
+        self.main_loop.quit();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        info!("RTSP server stopped");
+    }
+}
+
+/// Hand a branch sample to the media, which timestamps it on arrival
+fn forward(appsrc: &gst_app::AppSrc, sample: &gst::Sample) -> Result<(), gst::FlowError> {
+    let Some(mut buffer) = sample.buffer_owned() else {
+        return Ok(());
+    };
+    {
+        let buffer = buffer.make_mut();
+        buffer.set_pts(gst::ClockTime::NONE);
+        buffer.set_dts(gst::ClockTime::NONE);
+    }
+    let caps = sample.caps_owned();
+    let mut forwarded = gst::Sample::builder().buffer(&buffer);
+    if let Some(caps) = &caps {
+        forwarded = forwarded.caps(caps);
+    }
+    appsrc.push_sample(&forwarded.build()).map(|_| ())
+}
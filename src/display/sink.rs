@@ -0,0 +1,244 @@
+//! Video sink selection, including detection from the session environment
+
+use std::{env, fs, sync::OnceLock};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::builder::{BuildError, PipelineBuilder};
+use crate::DisplayConfig;
+
+/// Sink picked for `Auto`; the session does not change while Apollo runs
+static DETECTED: OnceLock<VideoSink> = OnceLock::new();
+
+/// Where the display pipeline renders
+///
+/// The old element names (`xvimagesink`, ...) are still accepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VideoSink {
+    /// Picked from the session: Wayland, then X11, then KMS, then headless
+    #[default]
+    Auto,
+    /// X11 XVideo
+    #[serde(alias = "xvimagesink")]
+    Xvimage,
+    /// OpenGL, under X11 or Wayland
+    #[serde(alias = "glimagesink")]
+    Glimage,
+    #[serde(alias = "waylandsink")]
+    Wayland,
+    /// Direct DRM/KMS output for kiosks without a compositor
+    #[serde(alias = "kmssink")]
+    Kms,
+    /// Discard frames, for headless benchmarking
+    #[serde(alias = "fakesink")]
+    Fake,
+    /// Write MJPEG in Matroska to `display.sink_path`
+    #[serde(alias = "filesink")]
+    File,
+}
+
+impl VideoSink {
+    /// The concrete sink, resolving `Auto` from the session environment
+    ///
+    /// Detection runs, and is logged, on the first call only.
+    pub fn resolve(self) -> VideoSink {
+        self.resolve_in(&DETECTED, detect)
+    }
+
+    /// `resolve`, caching the detected sink in `detected`
+    fn resolve_in(
+        self,
+        detected: &OnceLock<VideoSink>,
+        detect: impl FnOnce() -> VideoSink,
+    ) -> VideoSink {
+        if self != VideoSink::Auto {
+            return self;
+        }
+
+        *detected.get_or_init(|| {
+            let sink = detect();
+            info!("Auto-selected {:?} video sink", sink);
+            sink
+        })
+    }
+
+    /// Elements the sink needs
+    pub fn elements(self) -> &'static [&'static str] {
+        match self.resolve() {
+            VideoSink::Auto | VideoSink::Xvimage => &["xvimagesink"],
+            VideoSink::Glimage => &["glimagesink"],
+            VideoSink::Wayland => &["waylandsink"],
+            VideoSink::Kms => &["kmssink"],
+            VideoSink::Fake => &["fakesink"],
+            VideoSink::File => &["videoconvert", "jpegenc", "matroskamux", "filesink"],
+        }
+    }
+
+    /// Element name, as substituted for `{sink}` in custom pipelines
+    pub fn factory(self) -> &'static str {
+        let elements = self.elements();
+        elements[elements.len() - 1]
+    }
+}
+
+/// What `Auto` detection looks at in the session
+#[derive(Debug, Clone, Copy, Default)]
+struct Session {
+    wayland: bool,
+    x11: bool,
+    drm: bool,
+}
+
+impl Session {
+    fn current() -> Self {
+        Self {
+            wayland: env::var_os("WAYLAND_DISPLAY").is_some(),
+            x11: env::var_os("DISPLAY").is_some(),
+            drm: has_drm_card(),
+        }
+    }
+}
+
+fn detect() -> VideoSink {
+    choose(Session::current(), |factory| {
+        gst::ElementFactory::find(factory).is_some()
+    })
+    .unwrap_or_else(|| {
+        warn!("No display session or installed display sink found; discarding frames");
+        VideoSink::Fake
+    })
+}
+
+/// Wayland, then X11, then KMS when a DRM card exists, skipping sinks that
+/// are not `installed`
+fn choose(session: Session, installed: impl Fn(&str) -> bool) -> Option<VideoSink> {
+    let candidates = [
+        (session.wayland, VideoSink::Wayland),
+        (session.x11, VideoSink::Xvimage),
+        // Xv is often missing under XWayland and in VMs; GL covers both sessions
+        (session.x11 || session.wayland, VideoSink::Glimage),
+        (session.drm, VideoSink::Kms),
+    ];
+
+    candidates
+        .into_iter()
+        .filter(|&(usable, _)| usable)
+        .map(|(_, sink)| sink)
+        .find(|sink| installed(sink.factory()))
+}
+
+fn has_drm_card() -> bool {
+    fs::read_dir("/dev/dri").is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|entry| entry.file_name().to_string_lossy().starts_with("card"))
+    })
+}
+
+/// Create `sink`, as resolved by the caller, with scaling left to the pipeline where supported
+pub fn video_sink(sink: VideoSink, display: &DisplayConfig) -> Result<gst::Element, BuildError> {
+    if sink == VideoSink::File {
+        return file_sink(&display.sink_path);
+    }
+
+    let element = PipelineBuilder::element(sink.factory(), "videosink")?;
+    if matches!(sink, VideoSink::Xvimage | VideoSink::Glimage) {
+        element.set_property("force-aspect-ratio", false);
+    }
+    Ok(element)
+}
+
+/// A bin encoding to MJPEG in Matroska, which stays playable without a clean shutdown
+fn file_sink(path: &str) -> Result<gst::Element, BuildError> {
+    let mut builder = PipelineBuilder::bin("videosink");
+    builder.stage("videoconvert", "fileconvert")?;
+    builder.stage("jpegenc", "fileenc")?;
+    builder.stage("matroskamux", "filemux")?;
+    builder
+        .stage("filesink", "file")?
+        .set_property("location", path);
+    let (bin, first) = builder.finish_bin();
+
+    let target = first
+        .and_then(|convert| convert.static_pad("sink"))
+        .expect("videoconvert has a sink pad");
+    gst::GhostPad::with_target(&target)
+        .and_then(|pad| bin.add_pad(&pad))
+        .map_err(|source| BuildError::Add {
+            name: "videosink ghost pad".to_string(),
+            source,
+        })?;
+    Ok(bin.upcast())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(wayland: bool, x11: bool, drm: bool) -> Session {
+        Session { wayland, x11, drm }
+    }
+
+    fn all_installed(_: &str) -> bool {
+        true
+    }
+
+    #[test]
+    fn prefers_wayland_then_x11_then_kms() {
+        assert_eq!(
+            choose(session(true, true, true), all_installed),
+            Some(VideoSink::Wayland)
+        );
+        assert_eq!(
+            choose(session(false, true, true), all_installed),
+            Some(VideoSink::Xvimage)
+        );
+        assert_eq!(
+            choose(session(false, false, true), all_installed),
+            Some(VideoSink::Kms)
+        );
+        assert_eq!(choose(Session::default(), all_installed), None);
+    }
+
+    #[test]
+    fn falls_back_to_gl_when_the_session_sink_is_missing() {
+        let without = |missing: &'static str| move |factory: &str| factory != missing;
+
+        assert_eq!(
+            choose(session(true, false, true), without("waylandsink")),
+            Some(VideoSink::Glimage)
+        );
+        assert_eq!(
+            choose(session(false, true, false), without("xvimagesink")),
+            Some(VideoSink::Glimage)
+        );
+        assert_eq!(
+            choose(session(false, false, true), without("kmssink")),
+            None
+        );
+        assert_eq!(choose(session(true, true, true), |_| false), None);
+    }
+
+    #[test]
+    fn auto_is_detected_once() {
+        let detected = OnceLock::new();
+
+        assert_eq!(
+            VideoSink::Kms.resolve_in(&detected, || unreachable!()),
+            VideoSink::Kms
+        );
+        assert_eq!(detected.get(), None);
+
+        assert_eq!(
+            VideoSink::Auto.resolve_in(&detected, || VideoSink::Wayland),
+            VideoSink::Wayland
+        );
+        assert_eq!(
+            VideoSink::Auto.resolve_in(&detected, || unreachable!()),
+            VideoSink::Wayland
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Keeps the display running across camera unplug/re-plug
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use tracing::{error, info, warn};
+
+use super::{
+    builder::PipelineBuilder,
+    display::run_pipeline,
+    sink::{video_sink, VideoSink},
+};
+use crate::{
+    utils::{find_device, probe_device, DeviceIdentity},
+    CaptureConfig, DisplayConfig, GStreamerConfig,
+};
+
+/// Upper bound for the doubling reconnect delay
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Run the pipeline, rebuilding it whenever the capture device comes back
+///
+/// Errors unrelated to device loss are returned unchanged, as are losses once
+/// `reconnect_max_retries` attempts have passed without the device reappearing.
+/// Rebuilds use the current [`CONFIG`](crate::CONFIG), so hot-reloaded settings
+/// survive a reconnect, and write the File sink to a new segment.
+pub fn run_supervised(
+    capture: &CaptureConfig,
+    display: &DisplayConfig,
+    gstreamer: &GStreamerConfig,
+) -> Result<()> {
+    let identity = probe_device(&capture.device.path)
+        .map(|device| device.identity())
+        .ok();
+    let mut capture = capture.clone();
+    let mut display = display.clone();
+    let mut gstreamer = gstreamer.clone();
+    let mut segment = 0;
+
+    loop {
+        let err = match run_pipeline(&capture, &display, &gstreamer) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        let Some(identity) = identity.as_ref() else {
+            return Err(err);
+        };
+        if capture.reconnect_max_retries == 0 || !device_lost(&capture.device.path, identity) {
+            return Err(err);
+        }
+
+        warn!("Capture device {} lost: {}", capture.device.path, err);
+        let path = wait_for_device(identity, &capture, &display)?;
+        info!("Capture device is back at {}", path);
+
+        let mut config = (*crate::CONFIG.load_full()).clone();
+        config.capture.device.path = path;
+        capture = config.capture.clone();
+        display = config.display.clone();
+        gstreamer = config.gstreamer.clone();
+        crate::CONFIG.store(Arc::new(config));
+
+        // Keep what was written before the loss instead of truncating it
+        if display.sink.resolve() == VideoSink::File {
+            let (path, next) = next_segment(&display.sink_path, segment + 1);
+            segment = next;
+            info!("Continuing the file output in {}", path.display());
+            display.sink_path = path.to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// The first `<stem>-<n>.<ext>` beside `path`, from `first` on, that does not exist yet
+fn next_segment(path: &str, first: u32) -> (PathBuf, u32) {
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .map_or_else(|| "apollo".into(), |stem| stem.to_string_lossy());
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut n = first;
+    loop {
+        let candidate = path.with_file_name(format!("{}-{}{}", stem, n, extension));
+        if !candidate.exists() {
+            return (candidate, n);
+        }
+        n += 1;
+    }
+}
+
+/// The node is gone or now belongs to a different camera
+fn device_lost(path: &str, identity: &DeviceIdentity) -> bool {
+    if !Path::new(path).exists() {
+        return true;
+    }
+    probe_device(path).map_or(true, |device| !identity.matches(&device))
+}
+
+/// Show the no-signal slate and poll until the camera reappears
+fn wait_for_device(
+    identity: &DeviceIdentity,
+    capture: &CaptureConfig,
+    display: &DisplayConfig,
+) -> Result<String> {
+    let slate = match start_slate(display) {
+        Ok(slate) => Some(slate),
+        Err(e) => {
+            error!("Failed to show no-signal slate: {}", e);
+            None
+        }
+    };
+
+    let delays = backoff_delays(capture.reconnect_backoff_ms, capture.reconnect_max_retries);
+    let mut found = None;
+    for (attempt, delay) in (1..).zip(delays) {
+        thread::sleep(delay);
+
+        if let Some(device) = find_device(identity) {
+            found = Some(device.path);
+            break;
+        }
+
+        info!(
+            "Waiting for {} ({}/{} attempts)",
+            identity.card, attempt, capture.reconnect_max_retries
+        );
+    }
+
+    if let Some(slate) = slate {
+        slate.set_state(gst::State::Null).ok();
+    }
+
+    found.ok_or_else(|| {
+        eyre!(
+            "Capture device {} did not reappear after {} attempts",
+            identity.card,
+            capture.reconnect_max_retries
+        )
+    })
+}
+
+/// The wait before each of `retries` attempts: doubling from `initial_ms` up to [`MAX_BACKOFF`]
+fn backoff_delays(initial_ms: u32, retries: u32) -> impl Iterator<Item = Duration> {
+    let first = Duration::from_millis(initial_ms.into());
+    std::iter::successors(Some(first), |delay| Some((*delay * 2).min(MAX_BACKOFF)))
+        .take(retries as usize)
+}
+
+/// A black "No signal" screen at the display size
+fn start_slate(display: &DisplayConfig) -> Result<gst::Pipeline> {
+    let mut builder = PipelineBuilder::new("slate");
+    let source = builder.stage("videotestsrc", "source")?;
+    source.set_property_from_str("pattern", "black");
+    source.set_property("is-live", true);
+    builder.caps(
+        "slatecaps",
+        &gst::Caps::builder("video/x-raw")
+            .field("width", display.width as i32)
+            .field("height", display.height as i32)
+            .build(),
+    )?;
+    let text = builder.stage("textoverlay", "text")?;
+    text.set_property("text", "No signal");
+    text.set_property_from_str("valignment", "center");
+    text.set_property_from_str("halignment", "center");
+    text.set_property("font-desc", "Sans 36");
+    builder.stage("videoconvert", "convert")?;
+    // Keep the recording from being overwritten by the slate
+    let sink = match display.sink.resolve() {
+        VideoSink::File => VideoSink::Fake,
+        sink => sink,
+    };
+    builder.push(video_sink(sink, display)?)?;
+
+    let (slate, _) = builder.finish();
+    slate
+        .set_state(gst::State::Playing)
+        .map_err(|_| eyre!("Failed to start no-signal slate"))?;
+    Ok(slate)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let delays: Vec<_> = backoff_delays(500, 8).map(|d| d.as_secs_f64()).collect();
+        assert_eq!(delays, [0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 30.0, 30.0]);
+
+        assert_eq!(backoff_delays(500, 0).count(), 0);
+        assert_eq!(
+            backoff_delays(0, 3).collect::<Vec<_>>(),
+            [Duration::ZERO; 3]
+        );
+    }
+
+    #[test]
+    fn next_segment_skips_existing_files() {
+        let dir = std::env::temp_dir().join(format!("apollo-segments-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("capture.mkv");
+        let output = output.to_str().unwrap();
+
+        assert_eq!(next_segment(output, 1), (dir.join("capture-1.mkv"), 1));
+
+        fs::write(dir.join("capture-1.mkv"), b"").unwrap();
+        fs::write(dir.join("capture-2.mkv"), b"").unwrap();
+        assert_eq!(next_segment(output, 1), (dir.join("capture-3.mkv"), 3));
+        assert_eq!(next_segment(output, 5), (dir.join("capture-5.mkv"), 5));
+
+        let bare = dir.join("capture");
+        assert_eq!(
+            next_segment(bare.to_str().unwrap(), 1),
+            (dir.join("capture-1"), 1)
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,42 +1,64 @@
 pub mod capture;
 pub mod display;
+pub mod settings;
 pub mod utils;
+pub mod validation;
 
 use arc_swap::ArcSwap;
-use capture::frame::PixelFormat;
+use capture::{frame::PixelFormat, CaptureBackend, ModePolicy};
 use serde::{Deserialize, Serialize};
 
-use crate::utils::FoundDevice;
+use crate::{
+    display::{
+        decoder::{H264_DECODERS, H265_DECODERS, JPEG_DECODERS},
+        OverlayPosition, RecordingCodec, RecordingContainer, RecordingMode, Roi, ScalingMode,
+        VideoSink,
+    },
+    utils::FoundDevice,
+};
 
 /// Global configuration that can be atomically swapped at runtime
 pub static CONFIG: once_cell::sync::Lazy<ArcSwap<Config>> =
     once_cell::sync::Lazy::new(|| ArcSwap::from_pointee(Config::default()));
 
 /// System configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub capture: CaptureConfig,
     pub display: DisplayConfig,
     pub pipeline: PipelineConfig,
     pub gstreamer: GStreamerConfig,
+    pub recording: RecordingConfig,
+    pub rtsp: RtspConfig,
+    pub http: HttpConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CaptureConfig {
+    pub backend: CaptureBackend,
     pub device: FoundDevice,
     pub width: u32,
     pub height: u32,
     pub fps: u32,
+    #[serde(skip)]
+    pub frame_interval: Option<(u32, u32)>, // Exact negotiated seconds per frame, e.g. 1001/30000
     pub format: PixelFormat,
+    pub mode_policy: ModePolicy, // Fallback when the exact mode is unsupported
     pub buffer_count: u32,
-    pub use_mmap: bool,   // Memory-mapped I/O
-    pub use_dmabuf: bool, // DMA-BUF for zero-copy to GPU
+    pub use_mmap: bool,             // Memory-mapped I/O
+    pub use_dmabuf: bool,           // DMA-BUF for zero-copy to GPU
+    pub reconnect_max_retries: u32, // Attempts after the device disappears, 0 to exit instead
+    pub reconnect_backoff_ms: u32,  // First retry delay, doubled after each attempt
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DisplayConfig {
     pub width: u32,
     pub height: u32,
+    pub scaling: ScalingMode,
+    pub roi: Option<Roi>, // Part of the captured frame to show, in capture pixels
+    pub sink: VideoSink,
+    pub sink_path: String, // Output file for the File sink
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -48,42 +70,88 @@ pub enum GpuBackend {
     Auto,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PipelineConfig {
-    pub ring_buffer_size: usize,
     pub decode_threads: usize,
     pub enable_profiling: bool,
     pub target_latency_ms: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GStreamerConfig {
-    pub use_hardware_acceleration: bool,
+    pub use_hardware_acceleration: bool, // false restricts decoding to software decoders
+    pub jpeg_decoders: Vec<String>,      // Tried in order; jpegdec is always the last resort
+    pub h264_decoders: Vec<String>,      // Tried in order; avdec_h264 is always the last resort
+    pub h265_decoders: Vec<String>,      // Tried in order; avdec_h265 is always the last resort
     pub prefer_zero_copy: bool,
     pub custom_pipeline: Option<String>,
     pub enable_fps_overlay: bool,
+    pub stats_overlay: bool, // Capture/display FPS, drops, decoder and latency
+    pub overlay_position: OverlayPosition,
+    pub overlay_font_size: u32,
     pub buffer_pool_size: u32,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordingConfig {
+    pub enabled: bool, // Toggle while running to start and stop recording
+    pub mode: RecordingMode,
+    pub codec: RecordingCodec, // Encode mode only
+    pub container: RecordingContainer,
+    pub bitrate_kbps: u32,    // Encode mode only
+    pub segment_seconds: u32, // Start a new file after this long, 0 for a single file
+    pub output_dir: String,
+    pub flight_recorder: bool, // Keep recent frames in memory to save on a trigger
+    pub pre_event_seconds: u32,
+    pub post_event_seconds: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RtspConfig {
+    pub enabled: bool, // Needs a build with `--features rtsp`
+    pub port: u16,
+    pub mount: String,            // Path clients open, e.g. rtsp://host:8554/live
+    pub mode: RecordingMode,      // Encode to H.264, or Passthrough the camera's MJPEG
+    pub bitrate_kbps: u32,        // Encode mode only
+    pub username: Option<String>, // Basic authentication when set with a password
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HttpConfig {
+    pub enabled: bool, // Serves /stream.mjpg and /snapshot.jpg
+    pub port: u16,
+    pub max_fps: u32,      // Per-client frame-rate cap, 0 for every frame
+    pub jpeg_quality: u32, // Re-encoding of non-MJPEG sources only, 1-100
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             capture: CaptureConfig {
+                backend: CaptureBackend::GStreamer,
                 device: FoundDevice::new("/dev/video0".into(), PixelFormat::Mjpeg.into()),
                 width: 1920,
                 height: 1080,
                 fps: 30,
+                frame_interval: None,
                 format: PixelFormat::Mjpeg,
+                mode_policy: ModePolicy::PreferResolution,
                 buffer_count: 4,
                 use_mmap: true,
                 use_dmabuf: false, // Requires kernel 5.19+
+                reconnect_max_retries: 10,
+                reconnect_backoff_ms: 500,
             },
             display: DisplayConfig {
                 width: 1920,
                 height: 600,
+                scaling: ScalingMode::Fit,
+                roi: None,
+                sink: VideoSink::Auto,
+                sink_path: "apollo.mkv".into(),
             },
             pipeline: PipelineConfig {
-                ring_buffer_size: 8,
                 decode_threads: 2,
                 enable_profiling: false,
                 // target_latency_ms: 16, // 60fps target
@@ -92,11 +160,44 @@ impl Default for Config {
             #[cfg(feature = "gstreamer-pipeline")]
             gstreamer: GStreamerConfig {
                 use_hardware_acceleration: true,
+                jpeg_decoders: JPEG_DECODERS.iter().map(|&name| name.into()).collect(),
+                h264_decoders: H264_DECODERS.iter().map(|&name| name.into()).collect(),
+                h265_decoders: H265_DECODERS.iter().map(|&name| name.into()).collect(),
                 prefer_zero_copy: true,
                 custom_pipeline: None,
                 enable_fps_overlay: true,
+                stats_overlay: false,
+                overlay_position: OverlayPosition::BottomLeft,
+                overlay_font_size: 14,
                 buffer_pool_size: 4,
             },
+            recording: RecordingConfig {
+                enabled: false,
+                mode: RecordingMode::Encode,
+                codec: RecordingCodec::H264,
+                container: RecordingContainer::Mkv,
+                bitrate_kbps: 8000,
+                segment_seconds: 300,
+                output_dir: "recordings".into(),
+                flight_recorder: false,
+                pre_event_seconds: 10,
+                post_event_seconds: 10,
+            },
+            rtsp: RtspConfig {
+                enabled: false,
+                port: 8554,
+                mount: "/live".into(),
+                mode: RecordingMode::Encode,
+                bitrate_kbps: 4000,
+                username: None,
+                password: None,
+            },
+            http: HttpConfig {
+                enabled: false,
+                port: 8080,
+                max_fps: 15,
+                jpeg_quality: 85,
+            },
         }
     }
 }
@@ -109,6 +210,8 @@ pub struct Metrics {
     pub dropped_frames: u64,
     pub avg_latency_ms: f64,
     pub p99_latency_ms: f64,
+    pub rtsp_clients: usize,
+    pub http_clients: usize,
     pub decode_time_us: u64,
     pub render_time_us: u64,
 }
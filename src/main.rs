@@ -1,35 +1,119 @@
 //! Apollo Video Pipeline with GStreamer or SDL2 Display
 
+mod cli;
+
 use std::sync::Arc;
 
-use apollo::{display::run_pipeline, utils::auto_detect_device, Config};
-use color_eyre::Result;
-use tracing::{error, info};
+use apollo::{
+    capture::{fit_to_capture, is_network_uri, negotiate_mode},
+    display::{
+        check_elements, check_http, check_recording, run_supervised, serve_http, trigger_event,
+    },
+    settings::{spawn_config_watcher, ConfigSources},
+    utils::{auto_detect_device, list_capture_devices, resolve_device, DeviceInfo},
+    Config,
+};
+use clap::Parser;
+use color_eyre::{eyre::eyre, Result};
+use tracing::{error, info, warn};
+
+use crate::cli::{Args, Command};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize error handling and logging
+    let args = Args::parse();
+
+    // Initialize error handling and logging; stdout is reserved for command output
     color_eyre::install()?;
     tracing_subscriber::fmt()
         .with_env_filter("apollo=debug")
         .with_timer(tracing_subscriber::fmt::time::uptime())
+        .with_writer(std::io::stderr)
         .init();
 
+    match args.command() {
+        Command::Run(_) => run(args.config_sources()).await,
+        Command::ListDevices => list_devices(),
+        Command::Probe { device } => probe(&device),
+        Command::PrintConfig => print_config(&args.config_sources()),
+        Command::Check => check(&args.config_sources()),
+    }
+}
+
+async fn run(sources: ConfigSources) -> Result<()> {
     info!("Apollo Launching...");
 
     // Load configuration
-    let config = Config::default();
+    let config = Config::load(&sources)?;
+    config.validate()?;
     apollo::CONFIG.store(Arc::new(config.clone()));
+    spawn_config_watcher(sources, config.clone());
 
     let mut capture_config = config.capture.clone();
-    if capture_config.device.path.is_empty() {
-        let device = auto_detect_device().await?;
-        capture_config.device = device;
+    // Only V4L2 backends resolve a device node; other sources name their own input
+    if capture_config.backend.uses_v4l2_device() && !is_network_uri(&capture_config.device.path) {
+        if capture_config.device.path.is_empty() {
+            let device = auto_detect_device().await?;
+            // The device was picked for this format, so capture in it
+            if device.format != capture_config.format {
+                info!(
+                    "Capturing {:?} from {} instead of {:?}",
+                    device.format, device.path, capture_config.format
+                );
+            }
+            capture_config.format = device.format;
+            capture_config.device = device;
+        } else {
+            let device = resolve_device(&capture_config.device.path)?;
+            if device.path != capture_config.device.path {
+                info!(
+                    "Resolved capture device `{}` to {}",
+                    capture_config.device.path, device.path
+                );
+            }
+            capture_config.device.path = device.path;
+        }
+
+        // Settle on a mode the device supports
+        negotiate_mode(&mut capture_config)?;
+    }
+    info!(
+        "Using {:?} capture device: {:?}",
+        capture_config.backend, capture_config.device
+    );
+
+    // Publish the settled capture settings as the effective config
+    let mut effective = config.clone();
+    effective.capture = capture_config.clone();
+    fit_to_capture(&mut effective);
+    apollo::CONFIG.store(Arc::new(effective.clone()));
+
+    // `kill -USR1` saves a flight recorder event
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined1()) {
+            Ok(mut usr1) => {
+                while usr1.recv().await.is_some() {
+                    trigger_event();
+                }
+            }
+            Err(e) => warn!("Cannot listen for SIGUSR1: {}", e),
+        }
+    });
+
+    // The HTTP server outlives pipeline restarts, so viewers stay connected
+    if config.http.enabled {
+        let http = config.http.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_http(http).await {
+                error!("HTTP server stopped: {}", e);
+            }
+        });
     }
-    info!("Using capture device: {:?}", capture_config.device);
 
-    // Run the simple pipeline that should size the window correctly
-    match run_pipeline(&capture_config, &config.display) {
+    // Run the pipeline, reconnecting if the camera is unplugged
+    match run_supervised(&capture_config, &effective.display, &config.gstreamer) {
         Ok(_) => info!("Pipeline completed successfully"),
         Err(e) => error!("Pipeline error: {}", e),
     }
@@ -37,3 +121,108 @@ async fn main() -> Result<()> {
     info!("Apollo shutting down");
     Ok(())
 }
+
+fn list_devices() -> Result<()> {
+    let devices = list_capture_devices();
+    if devices.is_empty() {
+        return Err(eyre!("No capture devices found"));
+    }
+
+    println!(
+        "{:<14} {:<28} {:<24} {:<6} {:<11} FPS",
+        "DEVICE", "CARD", "BUS", "FORMAT", "SIZE"
+    );
+    for device in &devices {
+        for (fourcc, size, rates) in mode_rows(device) {
+            println!(
+                "{:<14} {:<28} {:<24} {:<6} {:<11} {}",
+                device.path, device.card, device.bus_info, fourcc, size, rates
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn probe(selector: &str) -> Result<()> {
+    let device = resolve_device(selector)?;
+
+    println!("Device:   {}", device.path);
+    println!("Card:     {}", device.card);
+    println!("Driver:   {}", device.driver);
+    println!("Bus:      {}", device.bus_info);
+    println!("Serial:   {}", device.serial.as_deref().unwrap_or("(none)"));
+    for link in &device.links {
+        println!("Link:     {}", link);
+    }
+    println!("Formats:");
+    for (fourcc, description) in &device.formats {
+        println!("  {:<6} {}", fourcc, description);
+    }
+    println!("Modes:");
+    for (fourcc, size, rates) in mode_rows(&device) {
+        println!("  {:<6} {:<11} {} fps", fourcc, size, rates);
+    }
+
+    Ok(())
+}
+
+/// Group a device's modes into (fourcc, size, frame rates) rows
+fn mode_rows(device: &DeviceInfo) -> Vec<(String, String, String)> {
+    let mut rows: Vec<(String, String, Vec<String>)> = Vec::new();
+    for mode in &device.modes {
+        // Drivers that do not enumerate sizes or intervals leave them unknown
+        let size = if mode.has_size() {
+            format!("{}x{}", mode.width, mode.height)
+        } else {
+            "-".to_string()
+        };
+        let fps = if mode.interval.0 > 0 {
+            format!("{}", (mode.fps() * 100.0).round() / 100.0)
+        } else {
+            "-".to_string()
+        };
+        match rows.last_mut() {
+            Some((fourcc, last, rates)) if *fourcc == mode.fourcc && *last == size => {
+                rates.push(fps)
+            }
+            _ => rows.push((mode.fourcc.clone(), size, vec![fps])),
+        }
+    }
+
+    rows.into_iter()
+        .map(|(fourcc, size, rates)| (fourcc, size, rates.join(", ")))
+        .collect()
+}
+
+fn print_config(sources: &ConfigSources) -> Result<()> {
+    let config = Config::load(sources)?;
+    print!("{}", toml::to_string_pretty(&config)?);
+    Ok(())
+}
+
+fn check(sources: &ConfigSources) -> Result<()> {
+    let config = Config::load(sources)?;
+    config.validate()?;
+    info!("Configuration is valid");
+
+    check_elements(&config.capture, &config.display, &config.gstreamer)?;
+    info!("All required GStreamer elements are available");
+
+    if config.recording.enabled || config.recording.flight_recorder {
+        check_recording(&config.recording, &config.gstreamer)?;
+        info!("Recording can start");
+    }
+    if config.http.enabled {
+        check_http(&config.capture)?;
+        info!("HTTP output can start");
+    }
+    #[cfg(feature = "rtsp")]
+    if config.rtsp.enabled {
+        apollo::display::check_rtsp(&config.rtsp, &config.gstreamer)?;
+        info!("RTSP server can start");
+    }
+
+    println!("OK");
+    Ok(())
+}
@@ -0,0 +1,393 @@
+//! Layered configuration loading: defaults, `apollo.toml`, `APOLLO_*` environment, CLI
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use config::{Environment, File, FileFormat, Map, Source, Value, ValueKind};
+use tracing::{debug, info, warn};
+
+use crate::{capture::fit_to_capture, Config, CONFIG};
+
+/// Config file picked up from the working directory when no path is given
+pub const DEFAULT_CONFIG_FILE: &str = "apollo.toml";
+
+/// Prefix for environment overrides, e.g. `APOLLO_CAPTURE__WIDTH=1280`
+const ENV_PREFIX: &str = "APOLLO";
+
+/// Environment keys that select configuration rather than override it
+const RESERVED_ENV_KEYS: &[&str] = &["config"];
+
+/// Keys still accepted but ignored, with what to use instead
+const DEPRECATED_KEYS: &[(&str, &str)] = &[(
+    "pipeline.ring_buffer_size",
+    "the flight recorder keeps `recording.pre_event_seconds` instead",
+)];
+
+/// How often the config file's modification time is checked for hot-reload
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where each configuration layer comes from
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources {
+    /// TOML file merged over the defaults (`--config` / `APOLLO_CONFIG`)
+    pub file: Option<PathBuf>,
+    /// `key.path=value` overrides from the command line, applied last
+    pub overrides: Vec<(String, String)>,
+}
+
+impl ConfigSources {
+    /// The file to load: the explicit path, or `apollo.toml` if it exists
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.file.clone().or_else(|| {
+            let default = PathBuf::from(DEFAULT_CONFIG_FILE);
+            default.exists().then_some(default)
+        })
+    }
+}
+
+/// Record `section.field` in `changed` for every listed field that differs
+macro_rules! diff_fields {
+    ($changed:ident, $old:expr, $new:expr, $section:literal: $($field:ident),+ $(,)?) => {
+        $(
+            if $old.$field != $new.$field {
+                $changed.push(concat!($section, ".", stringify!($field)));
+            }
+        )+
+    };
+}
+
+impl Config {
+    /// Merge defaults, config file, environment and CLI overrides into a `Config`
+    pub fn load(sources: &ConfigSources) -> Result<Config> {
+        let defaults = config::Config::try_from(&Config::default())
+            .map_err(|e| eyre!("Failed to serialize default configuration: {}", e))?;
+        let known = defaults.cache.clone();
+
+        let mut builder = config::Config::builder().add_source(defaults);
+
+        if let Some(path) = sources.config_file() {
+            info!("Loading configuration from {}", path.display());
+            let file = config_file_source(&path);
+            let table = file
+                .collect()
+                .map_err(|e| eyre!("Failed to read config file: {}", e))?;
+            check_known_keys(&known, &table, "", &path.display().to_string())?;
+            builder = builder.add_source(file);
+        }
+
+        let env_keys = env_source()
+            .collect()
+            .map_err(|e| eyre!("Failed to read environment: {}", e))?;
+        for key in env_keys.keys() {
+            if RESERVED_ENV_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            debug!("Environment override: {}", key);
+            if is_deprecated(key) {
+                continue;
+            }
+            if !has_key(&known, key) {
+                return Err(eyre!(
+                    "Unknown configuration key `{}` in the environment ({}_{})",
+                    key,
+                    ENV_PREFIX,
+                    key.to_uppercase().replace('.', "__")
+                ));
+            }
+        }
+        builder = builder.add_source(env_source());
+
+        for (key, value) in &sources.overrides {
+            if is_deprecated(key) {
+                continue;
+            }
+            if !has_key(&known, key) {
+                return Err(eyre!(
+                    "Unknown configuration key `{}` on the command line",
+                    key
+                ));
+            }
+            builder = builder
+                .set_override(key.as_str(), value.as_str())
+                .map_err(|e| eyre!("Invalid override `{}={}`: {}", key, value, e))?;
+        }
+
+        builder
+            .build()
+            .and_then(|merged| merged.try_deserialize::<Config>())
+            .map_err(|e| eyre!("Invalid configuration: {}", e))
+    }
+
+    /// Changed fields that cannot be applied to a running pipeline
+    ///
+    /// The display size, scaling mode and ROI, `gstreamer.enable_fps_overlay`, the
+    /// `gstreamer` statistics overlay settings, `recording.enabled` and
+    /// `recording.flight_recorder` are applied live; the other `recording` fields
+    /// are read when a recording or the flight recorder starts, and
+    /// `http.max_fps` by each client for every frame. None of them are reported.
+    pub fn restart_required_changes(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        diff_fields!(changed, self.capture, new.capture, "capture":
+            backend, device, width, height, fps, format, mode_policy, buffer_count, use_mmap,
+            use_dmabuf, reconnect_max_retries, reconnect_backoff_ms);
+        diff_fields!(changed, self.display, new.display, "display": sink, sink_path);
+        diff_fields!(changed, self.pipeline, new.pipeline, "pipeline":
+            decode_threads, enable_profiling, target_latency_ms);
+        diff_fields!(changed, self.gstreamer, new.gstreamer, "gstreamer":
+            use_hardware_acceleration, jpeg_decoders, h264_decoders, h265_decoders,
+            prefer_zero_copy, custom_pipeline, buffer_pool_size);
+        diff_fields!(changed, self.rtsp, new.rtsp, "rtsp":
+            enabled, port, mount, mode, bitrate_kbps, username, password);
+        diff_fields!(changed, self.http, new.http, "http": enabled, port, jpeg_quality);
+
+        changed
+    }
+}
+
+/// Watch the config file and swap each valid revision into [`CONFIG`]
+///
+/// Revisions that fail to load or [`Config::validate`] are logged and ignored so
+/// the running configuration stays in place. The display pipeline picks up the
+/// live fields on its own; anything else is reported as requiring a restart.
+///
+/// Revisions are compared with `loaded`, the last configuration read from the
+/// sources, not with [`CONFIG`]: its capture section holds the resolved device
+/// and negotiated mode, which are kept when a revision is swapped in.
+pub fn spawn_config_watcher(
+    sources: ConfigSources,
+    loaded: Config,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let path = sources.config_file()?;
+    info!("Watching {} for changes", path.display());
+
+    Some(tokio::spawn(async move {
+        let mut last_modified = modified_time(&path);
+        let mut loaded = loaded;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let modified = modified_time(&path);
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            let reloaded = Config::load(&sources).and_then(|config| {
+                // The running capture settings stay until a restart
+                let mut effective = config.clone();
+                effective.capture = CONFIG.load().capture.clone();
+                fit_to_capture(&mut effective);
+                effective.validate()?;
+                Ok((config, effective))
+            });
+            match reloaded {
+                Ok((config, effective)) => {
+                    if loaded == config {
+                        debug!(
+                            "{} changed but the configuration is identical",
+                            path.display()
+                        );
+                        continue;
+                    }
+
+                    let restart = loaded.restart_required_changes(&config);
+                    if !restart.is_empty() {
+                        warn!(
+                            "Configuration changes require a restart to take effect: {}",
+                            restart.join(", ")
+                        );
+                    }
+
+                    info!("Reloaded configuration from {}", path.display());
+                    CONFIG.store(Arc::new(effective));
+                    loaded = config;
+                }
+                Err(e) => warn!(
+                    "Ignoring invalid configuration in {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }))
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn env_source() -> Environment {
+    Environment::with_prefix(ENV_PREFIX)
+        .prefix_separator("_")
+        .separator("__")
+        .try_parsing(true)
+        .ignore_empty(true)
+}
+
+fn config_file_source(path: &Path) -> File<config::FileSourceFile, FileFormat> {
+    File::from(path.to_path_buf())
+        .format(FileFormat::Toml)
+        .required(true)
+}
+
+/// Reject keys in `table` that have no counterpart in the defaults
+fn check_known_keys(
+    known: &Value,
+    table: &Map<String, Value>,
+    prefix: &str,
+    origin: &str,
+) -> Result<()> {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        if is_deprecated(&path) {
+            continue;
+        }
+        let Some(expected) = lookup(known, &path) else {
+            return Err(eyre!("Unknown configuration key `{}` in {}", path, origin));
+        };
+
+        // Only descend when both sides are tables; a scalar may stand in for a
+        // table (e.g. `device = "/dev/video0"`) and is type-checked on deserialize
+        if let (ValueKind::Table(_), ValueKind::Table(nested)) = (&expected.kind, &value.kind) {
+            check_known_keys(known, nested, &path, origin)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Warn if `path` is a deprecated key; its value is ignored
+fn is_deprecated(path: &str) -> bool {
+    let Some((_, instead)) = DEPRECATED_KEYS.iter().find(|(key, _)| *key == path) else {
+        return false;
+    };
+    warn!("`{}` is deprecated and ignored; {}", path, instead);
+    true
+}
+
+fn has_key(known: &Value, path: &str) -> bool {
+    lookup(known, path).is_some()
+}
+
+fn lookup<'a>(root: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(root, |value, segment| match &value.kind {
+            ValueKind::Table(table) => table.get(segment),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, sync::Mutex};
+
+    use super::*;
+
+    /// `Config::load` reads the whole `APOLLO_*` environment, so tests take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Load `toml` as the config file, with `env` set and `overrides` applied
+    fn load(
+        name: &str,
+        toml: &str,
+        env: &[(&str, &str)],
+        overrides: &[(&str, &str)],
+    ) -> Result<Config> {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let path = env::temp_dir().join(format!("apollo-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, toml).unwrap();
+        for (key, value) in env {
+            env::set_var(key, value);
+        }
+
+        let sources = ConfigSources {
+            file: Some(path.clone()),
+            overrides: overrides
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        let config = Config::load(&sources);
+
+        for (key, _) in env {
+            env::remove_var(key);
+        }
+        fs::remove_file(&path).ok();
+        config
+    }
+
+    #[test]
+    fn file_values_override_defaults() {
+        let config = load("file", "[capture]\nwidth = 1280\n", &[], &[]).unwrap();
+        assert_eq!(config.capture.width, 1280);
+        assert_eq!(config.capture.height, Config::default().capture.height);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = load("unknown", "[capture]\nwidht = 1280\n", &[], &[]).unwrap_err();
+        assert!(err.to_string().contains("`capture.widht`"), "{}", err);
+
+        let err = load("section", "[captur]\nwidth = 1280\n", &[], &[]).unwrap_err();
+        assert!(err.to_string().contains("`captur`"), "{}", err);
+
+        let err = load("override", "", &[], &[("capture.widht", "1280")]).unwrap_err();
+        assert!(err.to_string().contains("on the command line"), "{}", err);
+    }
+
+    #[test]
+    fn deprecated_keys_are_ignored() {
+        let config = load("deprecated", "[pipeline]\nring_buffer_size = 8\n", &[], &[]);
+        assert_eq!(config.unwrap(), Config::default());
+    }
+
+    #[test]
+    fn environment_maps_double_underscores_to_sections() {
+        let config = load(
+            "env",
+            "",
+            &[
+                ("APOLLO_CAPTURE__WIDTH", "1280"),
+                ("APOLLO_RECORDING__OUTPUT_DIR", "/var/recordings"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.capture.width, 1280);
+        assert_eq!(config.recording.output_dir, "/var/recordings");
+
+        let err = load("envtypo", "", &[("APOLLO_CAPTUR__WIDTH", "1280")], &[]).unwrap_err();
+        assert!(err.to_string().contains("APOLLO_CAPTUR__WIDTH"), "{}", err);
+    }
+
+    #[test]
+    fn command_line_overrides_win() {
+        let config = load(
+            "precedence",
+            "[capture]\nwidth = 800\nheight = 600\n",
+            &[
+                ("APOLLO_CAPTURE__WIDTH", "1024"),
+                ("APOLLO_CAPTURE__HEIGHT", "768"),
+            ],
+            &[("capture.width", "1280")],
+        )
+        .unwrap();
+        assert_eq!(config.capture.width, 1280);
+        // The environment still beats the file
+        assert_eq!(config.capture.height, 768);
+    }
+}
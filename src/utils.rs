@@ -1,16 +1,56 @@
+use std::path::Path;
+
 use crate::capture::frame::PixelFormat;
 use color_eyre::{eyre::eyre, Result};
 use serde::{Deserialize, Serialize};
-use tracing::info;
-use v4l::{capability::Flags, video::Capture, Device, FourCC};
+use tracing::{debug, info};
+use v4l::{
+    capability::Flags, frameinterval::FrameIntervalEnum, framesize::FrameSizeEnum, video::Capture,
+    Device, FourCC,
+};
+
+/// Sizes offered for drivers that report a stepwise/continuous range
+const COMMON_SIZES: &[(u32, u32)] = &[
+    (320, 240),
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 1024),
+    (1600, 1200),
+    (1920, 1080),
+    (2560, 1440),
+    (3840, 2160),
+];
+
+/// Frame rates offered for drivers that report a stepwise/continuous range
+const COMMON_FPS: &[u32] = &[5, 10, 15, 24, 25, 30, 50, 60, 90, 120];
 
 // Detected capture device info
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "DeviceSpec")]
 pub struct FoundDevice {
     pub path: String,
     pub format: PixelFormat,
 }
 
+/// Accepts either `device = "/dev/video0"` or a full `{ path, format }` table
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeviceSpec {
+    Path(String),
+    Full { path: String, format: PixelFormat },
+}
+
+impl From<DeviceSpec> for FoundDevice {
+    fn from(spec: DeviceSpec) -> Self {
+        match spec {
+            DeviceSpec::Path(path) => Self::new(path, PixelFormat::Mjpeg),
+            DeviceSpec::Full { path, format } => Self::new(path, format),
+        }
+    }
+}
+
 impl FoundDevice {
     pub fn new(path: String, format: PixelFormat) -> Self {
         Self { path, format }
@@ -19,42 +59,525 @@ impl FoundDevice {
 
 /// Auto-detect best capture device
 pub async fn auto_detect_device() -> Result<FoundDevice> {
-    use std::path::Path;
-
     info!("Auto-detecting capture devices...");
 
-    for i in 0..10 {
-        let path = format!("/dev/video{}", i);
-        if !Path::new(&path).exists() {
-            continue;
+    let devices = list_capture_devices();
+
+    // Prefer devices with MJPEG support, then H.264/H.265, then raw YUYV
+    for format in [
+        PixelFormat::Mjpeg,
+        PixelFormat::H264,
+        PixelFormat::H265,
+        PixelFormat::Yuyv4,
+    ] {
+        if let Some(device) = devices.iter().find(|dev| dev.supports(format)) {
+            info!(
+                "Found {:?} device: {} - {}",
+                format, device.path, device.card
+            );
+            return Ok(FoundDevice::new(device.path.clone(), format));
+        }
+    }
+
+    Err(eyre!("No suitable capture device found"))
+}
+
+/// Capture-capable V4L2 node and every mode it supports
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub card: String,
+    pub driver: String,
+    pub bus_info: String,
+    /// USB serial number, when the device exposes one
+    pub serial: Option<String>,
+    /// Stable `/dev/v4l/by-id` and `/dev/v4l/by-path` links to this node
+    pub links: Vec<String>,
+    /// FourCC and driver description, e.g. `("MJPG", "Motion-JPEG")`
+    pub formats: Vec<(String, String)>,
+    pub modes: Vec<DeviceMode>,
+}
+
+/// One (fourcc, size, frame interval) combination a device can capture
+///
+/// Drivers that do not enumerate sizes or intervals leave them zero.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceMode {
+    pub fourcc: String,
+    /// `None` for formats the pipeline cannot consume
+    pub format: Option<PixelFormat>,
+    pub width: u32,
+    pub height: u32,
+    /// Seconds per frame as (numerator, denominator), as reported by V4L2
+    pub interval: (u32, u32),
+}
+
+impl DeviceMode {
+    /// Whether the driver reported the frame size
+    pub fn has_size(&self) -> bool {
+        self.width > 0 && self.height > 0
+    }
+
+    pub fn fps(&self) -> f64 {
+        if self.interval.0 == 0 {
+            return 0.0;
         }
+        self.interval.1 as f64 / self.interval.0 as f64
+    }
+}
 
-        if let Ok(dev) = Device::with_path(&path) {
-            if let Ok(caps) = dev.query_caps() {
-                // Check for capture capability
-                if caps.capabilities.contains(Flags::VIDEO_CAPTURE) {
-                    // Prefer devices with MJPEG support
-                    if let Ok(formats) = dev.enum_formats() {
-                        for fmt in formats {
-                            if fmt.fourcc == FourCC::new(b"MJPG") {
-                                info!("Found MJPEG device: {} - {}", path, caps.card);
-                                return Ok(FoundDevice {
-                                    path,
-                                    format: PixelFormat::Mjpeg,
-                                });
-                            } else if fmt.fourcc == FourCC::new(b"YUYV") {
-                                info!("Found YUYV device: {} - {}", path, caps.card);
-                                return Ok(FoundDevice {
-                                    path,
-                                    format: PixelFormat::Yuyv4,
-                                });
-                            }
-                        }
-                    }
+impl DeviceInfo {
+    pub fn supports(&self, format: PixelFormat) -> bool {
+        self.modes.iter().any(|mode| mode.format == Some(format))
+    }
+
+    /// Whether a card name substring, serial, bus path or link name selects this device
+    pub fn matches(&self, selector: &str) -> bool {
+        self.card.to_lowercase().contains(&selector.to_lowercase())
+            || self.serial.as_deref() == Some(selector)
+            || self.bus_info == selector
+            || self
+                .links
+                .iter()
+                .any(|link| link.rsplit('/').next() == Some(selector))
+    }
+}
+
+/// What identifies a physical camera across re-plugs, independent of its node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub card: String,
+    pub serial: Option<String>,
+    pub bus_info: String,
+}
+
+impl DeviceIdentity {
+    /// Cameras with a serial match anywhere; others only on the same port
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        if device.card != self.card {
+            return false;
+        }
+        match &self.serial {
+            Some(serial) => device.serial.as_ref() == Some(serial),
+            None => device.bus_info == self.bus_info,
+        }
+    }
+}
+
+impl DeviceInfo {
+    pub fn identity(&self) -> DeviceIdentity {
+        DeviceIdentity {
+            card: self.card.clone(),
+            serial: self.serial.clone(),
+            bus_info: self.bus_info.clone(),
+        }
+    }
+}
+
+/// Find the current node of a previously seen camera
+pub fn find_device(identity: &DeviceIdentity) -> Option<DeviceInfo> {
+    list_capture_devices()
+        .into_iter()
+        .find(|device| identity.matches(device))
+}
+
+/// Resolve a device selector to the node it currently refers to
+///
+/// Paths under `/dev` (including `/dev/v4l/by-id` and `/dev/v4l/by-path`
+/// symlinks) are followed directly; anything else is matched against the card
+/// name, USB serial, bus path and link names of every capture device.
+pub fn resolve_device(selector: &str) -> Result<DeviceInfo> {
+    if selector.starts_with("/dev/") {
+        let node = std::fs::canonicalize(selector)
+            .map_err(|e| eyre!("Capture device {} is not present: {}", selector, e))?;
+        return probe_device(&node.to_string_lossy());
+    }
+
+    select_device(selector, &list_capture_devices())
+}
+
+/// The single device in `devices` that `selector` matches
+///
+/// Several matches are an error listing each candidate, so the user can pick
+/// a selector that tells them apart.
+fn select_device(selector: &str, devices: &[DeviceInfo]) -> Result<DeviceInfo> {
+    let mut matches: Vec<DeviceInfo> = devices
+        .iter()
+        .filter(|device| device.matches(selector))
+        .cloned()
+        .collect();
+
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => {
+            let available = devices
+                .iter()
+                .map(|device| format!("{} ({})", device.path, device.card))
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(eyre!(
+                "No capture device matches `{}` (available: {})",
+                selector,
+                if available.is_empty() {
+                    "none"
+                } else {
+                    &available
                 }
+            ))
+        }
+        _ => {
+            let candidates = matches
+                .iter()
+                .map(|device| {
+                    let serial = device.serial.as_deref().unwrap_or("no serial");
+                    format!(
+                        "{} ({}, {}, {})",
+                        device.path, device.card, serial, device.bus_info
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(eyre!(
+                "`{}` matches several capture devices: {}; use a serial, bus path or /dev/v4l link",
+                selector,
+                candidates
+            ))
+        }
+    }
+}
+
+/// List every `/dev/video*` node that supports video capture, in node order
+pub fn list_capture_devices() -> Vec<DeviceInfo> {
+    let Ok(entries) = std::fs::read_dir("/dev") else {
+        return Vec::new();
+    };
+
+    let mut nodes: Vec<(u32, String)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let index = name.strip_prefix("video")?.parse().ok()?;
+            Some((index, format!("/dev/{}", name)))
+        })
+        .collect();
+    nodes.sort();
+
+    nodes
+        .into_iter()
+        .filter_map(|(_, path)| match probe_device(&path) {
+            Ok(device) => Some(device),
+            Err(e) => {
+                debug!("Skipping {}: {}", path, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Query a single device node for its identity and capture modes
+pub fn probe_device(path: &str) -> Result<DeviceInfo> {
+    let dev = Device::with_path(path).map_err(|e| eyre!("Failed to open {}: {}", path, e))?;
+    let caps = dev
+        .query_caps()
+        .map_err(|e| eyre!("Failed to query {}: {}", path, e))?;
+    if !caps.capabilities.contains(Flags::VIDEO_CAPTURE) {
+        return Err(eyre!("{} is not a video capture device", path));
+    }
+
+    let descriptions = dev
+        .enum_formats()
+        .map_err(|e| eyre!("Failed to list formats of {}: {}", path, e))?;
+    // Metadata nodes of UVC cameras report capture capability but no formats
+    if descriptions.is_empty() {
+        return Err(eyre!("{} has no capture formats", path));
+    }
+
+    let mut formats = Vec::new();
+    let mut modes = Vec::new();
+    for desc in descriptions {
+        let fourcc = desc.fourcc.to_string();
+        let format = PixelFormat::from_fourcc(&desc.fourcc.repr);
+        modes.extend(format_modes(
+            &fourcc,
+            format,
+            frame_sizes(&dev, desc.fourcc),
+            |width, height| frame_intervals(&dev, desc.fourcc, width, height),
+        ));
+        formats.push((fourcc, desc.description));
+    }
+
+    Ok(DeviceInfo {
+        path: path.to_string(),
+        card: caps.card,
+        driver: caps.driver,
+        bus_info: caps.bus,
+        serial: usb_serial(path),
+        links: device_links(path),
+        formats,
+        modes,
+    })
+}
+
+/// Every size and interval of one format
+///
+/// Some drivers answer ENUM_FMT but not ENUM_FRAMESIZES or ENUM_FRAMEINTERVALS;
+/// their formats still get a mode, with the unknown size or interval left zero.
+fn format_modes(
+    fourcc: &str,
+    format: Option<PixelFormat>,
+    sizes: Vec<(u32, u32)>,
+    intervals: impl Fn(u32, u32) -> Vec<(u32, u32)>,
+) -> Vec<DeviceMode> {
+    let mode = |width, height, interval| DeviceMode {
+        fourcc: fourcc.to_string(),
+        format,
+        width,
+        height,
+        interval,
+    };
+    if sizes.is_empty() {
+        return vec![mode(0, 0, (0, 0))];
+    }
+
+    let mut modes = Vec::new();
+    for (width, height) in sizes {
+        let intervals = intervals(width, height);
+        if intervals.is_empty() {
+            modes.push(mode(width, height, (0, 0)));
+        }
+        modes.extend(
+            intervals
+                .into_iter()
+                .map(|interval| mode(width, height, interval)),
+        );
+    }
+    modes
+}
+
+/// Read the USB serial of the device behind a video node from sysfs
+fn usb_serial(path: &str) -> Option<String> {
+    let name = Path::new(path).file_name()?;
+    // `device` links to the USB interface; the serial belongs to its parent
+    let interface = std::fs::canonicalize(
+        Path::new("/sys/class/video4linux")
+            .join(name)
+            .join("device"),
+    )
+    .ok()?;
+    let serial = std::fs::read_to_string(interface.parent()?.join("serial")).ok()?;
+    let serial = serial.trim();
+    (!serial.is_empty()).then(|| serial.to_string())
+}
+
+/// Udev's persistent symlinks that currently point at `path`
+fn device_links(path: &str) -> Vec<String> {
+    let Ok(node) = std::fs::canonicalize(path) else {
+        return Vec::new();
+    };
+
+    let mut links = Vec::new();
+    for dir in ["/dev/v4l/by-id", "/dev/v4l/by-path"] {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if std::fs::canonicalize(entry.path()).is_ok_and(|target| target == node) {
+                links.push(entry.path().to_string_lossy().into_owned());
             }
         }
     }
+    links.sort();
+    links
+}
 
-    Err(eyre!("No suitable capture device found"))
+fn frame_sizes(dev: &Device, fourcc: FourCC) -> Vec<(u32, u32)> {
+    let Ok(sizes) = dev.enum_framesizes(fourcc) else {
+        return Vec::new();
+    };
+
+    let mut result = Vec::new();
+    for size in sizes {
+        match size.size {
+            FrameSizeEnum::Discrete(discrete) => result.push((discrete.width, discrete.height)),
+            FrameSizeEnum::Stepwise(range) => {
+                let fits = |value: u32, min: u32, max: u32, step: u32| {
+                    (min..=max).contains(&value) && (value - min) % step.max(1) == 0
+                };
+                result.push((range.min_width, range.min_height));
+                result.extend(COMMON_SIZES.iter().copied().filter(|&(w, h)| {
+                    fits(w, range.min_width, range.max_width, range.step_width)
+                        && fits(h, range.min_height, range.max_height, range.step_height)
+                }));
+                result.push((range.max_width, range.max_height));
+            }
+        }
+    }
+    result.dedup();
+    result
+}
+
+fn frame_intervals(dev: &Device, fourcc: FourCC, width: u32, height: u32) -> Vec<(u32, u32)> {
+    let Ok(intervals) = dev.enum_frameintervals(fourcc, width, height) else {
+        return Vec::new();
+    };
+
+    let mut result = Vec::new();
+    for interval in intervals {
+        match interval.interval {
+            FrameIntervalEnum::Discrete(fraction) => {
+                result.push((fraction.numerator, fraction.denominator))
+            }
+            FrameIntervalEnum::Stepwise(range) => {
+                // Intervals are seconds per frame, so the fastest rate is `min`
+                let seconds = |num: u32, den: u32| num as f64 / den.max(1) as f64;
+                let shortest = seconds(range.min.numerator, range.min.denominator);
+                let longest = seconds(range.max.numerator, range.max.denominator);
+                result.push((range.min.numerator, range.min.denominator));
+                result.extend(
+                    COMMON_FPS
+                        .iter()
+                        .rev()
+                        .map(|&fps| (1, fps))
+                        .filter(|&(num, den)| (shortest..=longest).contains(&seconds(num, den))),
+                );
+                result.push((range.max.numerator, range.max.denominator));
+            }
+        }
+    }
+    result.dedup();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(modes: Vec<DeviceMode>) -> DeviceInfo {
+        DeviceInfo {
+            path: "/dev/video0".into(),
+            card: "Test camera".into(),
+            driver: "uvcvideo".into(),
+            bus_info: "usb-0000:00:14.0-1".into(),
+            serial: None,
+            links: Vec::new(),
+            formats: vec![("MJPG".into(), "Motion-JPEG".into())],
+            modes,
+        }
+    }
+
+    #[test]
+    fn lists_every_size_and_interval() {
+        let modes = format_modes(
+            "MJPG",
+            Some(PixelFormat::Mjpeg),
+            vec![(1280, 720), (640, 480)],
+            |width, _| {
+                if width == 1280 {
+                    vec![(1, 30)]
+                } else {
+                    vec![(1, 60), (1, 30)]
+                }
+            },
+        );
+        let listed: Vec<_> = modes
+            .iter()
+            .map(|mode| (mode.width, mode.height, mode.interval))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (1280, 720, (1, 30)),
+                (640, 480, (1, 60)),
+                (640, 480, (1, 30))
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_without_enumerated_sizes_are_still_supported() {
+        let modes = format_modes("MJPG", Some(PixelFormat::Mjpeg), Vec::new(), |_, _| {
+            unreachable!("no sizes to ask intervals for")
+        });
+        assert_eq!(modes.len(), 1);
+        assert!(!modes[0].has_size());
+        assert_eq!(modes[0].fps(), 0.0);
+
+        let device = device(modes);
+        assert!(device.supports(PixelFormat::Mjpeg));
+        assert!(!device.supports(PixelFormat::Yuyv4));
+    }
+
+    #[test]
+    fn sizes_without_enumerated_intervals_keep_their_size() {
+        let modes = format_modes(
+            "YUYV",
+            Some(PixelFormat::Yuyv4),
+            vec![(640, 480)],
+            |_, _| Vec::new(),
+        );
+        assert_eq!(modes.len(), 1);
+        assert_eq!((modes[0].width, modes[0].height), (640, 480));
+        assert_eq!(modes[0].interval, (0, 0));
+    }
+
+    fn camera(path: &str, serial: Option<&str>, bus_info: &str) -> DeviceInfo {
+        DeviceInfo {
+            path: path.into(),
+            serial: serial.map(Into::into),
+            bus_info: bus_info.into(),
+            links: vec![format!("/dev/v4l/by-path/{}-video-index0", bus_info)],
+            ..device(Vec::new())
+        }
+    }
+
+    fn cameras() -> Vec<DeviceInfo> {
+        vec![
+            camera("/dev/video0", Some("A1B2"), "usb-0000:00:14.0-1"),
+            camera("/dev/video2", Some("C3D4"), "usb-0000:00:14.0-2"),
+            DeviceInfo {
+                card: "Integrated Webcam".into(),
+                ..camera("/dev/video4", None, "usb-0000:00:14.0-5")
+            },
+        ]
+    }
+
+    #[test]
+    fn selects_by_card_serial_bus_or_link() {
+        let devices = cameras();
+        let selected = |selector| select_device(selector, &devices).unwrap().path;
+
+        assert_eq!(selected("webcam"), "/dev/video4");
+        assert_eq!(selected("C3D4"), "/dev/video2");
+        assert_eq!(selected("usb-0000:00:14.0-1"), "/dev/video0");
+        assert_eq!(selected("usb-0000:00:14.0-2-video-index0"), "/dev/video2");
+    }
+
+    #[test]
+    fn ambiguous_selector_lists_the_candidates() {
+        let err = select_device("test camera", &cameras()).unwrap_err();
+        let message = err.to_string();
+
+        assert!(
+            message.contains("matches several capture devices"),
+            "{}",
+            message
+        );
+        assert!(message.contains("/dev/video0 (Test camera, A1B2, usb-0000:00:14.0-1)"));
+        assert!(message.contains("/dev/video2 (Test camera, C3D4, usb-0000:00:14.0-2)"));
+        assert!(!message.contains("/dev/video4"));
+    }
+
+    #[test]
+    fn unmatched_selector_lists_the_available_devices() {
+        let message = select_device("E5F6", &cameras()).unwrap_err().to_string();
+        assert!(
+            message.contains("No capture device matches `E5F6`"),
+            "{}",
+            message
+        );
+        assert!(message.contains("/dev/video4 (Integrated Webcam)"));
+
+        let message = select_device("E5F6", &[]).unwrap_err().to_string();
+        assert!(message.contains("(available: none)"), "{}", message);
+    }
 }
@@ -0,0 +1,445 @@
+//! Configuration checks run before a pipeline is built or a reload is applied
+
+use thiserror::Error;
+
+use crate::{
+    capture::{is_network_uri, CaptureBackend, PixelFormat},
+    display::{RecordingMode, VideoSink},
+    Config,
+};
+
+/// Every problem found in a configuration
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("invalid configuration:{}", list_issues(.issues))]
+pub struct ValidationError {
+    pub issues: Vec<InvalidField>,
+}
+
+/// A single invalid field or combination of fields
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum InvalidField {
+    #[error("`{field}` must be greater than zero")]
+    Zero { field: &'static str },
+
+    #[error("`{field}` is {value} but must be even for {format:?}")]
+    OddDimension {
+        field: &'static str,
+        value: u32,
+        format: PixelFormat,
+    },
+
+    #[error(
+        "`pipeline.target_latency_ms` ({target_latency_ms} ms) is shorter than one frame \
+         at {fps} fps ({frame_period_ms} ms)"
+    )]
+    LatencyBelowFramePeriod {
+        target_latency_ms: u32,
+        fps: u32,
+        frame_period_ms: u32,
+    },
+
+    #[error("`capture.backend` {backend:?} is not available in this build")]
+    BackendUnavailable { backend: CaptureBackend },
+
+    #[error("`capture.device` must name a file or directory for the {backend:?} backend")]
+    MissingSourcePath { backend: CaptureBackend },
+
+    #[error("stream URIs in `capture.device` need the GStreamer backend, not {backend:?}")]
+    NetworkNeedsGStreamer { backend: CaptureBackend },
+
+    #[error("`display.sink_path` must name an output file for the File sink")]
+    MissingSinkPath,
+
+    #[error("`recording.output_dir` must name a directory")]
+    MissingRecordingDir,
+
+    #[error(
+        "`{field}` Passthrough takes the camera's JPEG frames, but `capture.format` is {format:?}"
+    )]
+    PassthroughNeedsMjpeg {
+        field: &'static str,
+        format: PixelFormat,
+    },
+
+    #[error("`{field}` is {value} but must be between {min} and {max}")]
+    OutOfRange {
+        field: &'static str,
+        value: u32,
+        min: u32,
+        max: u32,
+    },
+
+    #[error("`{field}` Passthrough takes JPEG frames, but `{device}` is an H.264/H.265 stream")]
+    PassthroughNeedsJpegStream { field: &'static str, device: String },
+
+    #[error("`rtsp.enabled` needs a build with `--features rtsp`")]
+    RtspUnavailable,
+
+    #[error("`rtsp.mount` must start with `/`, got `{mount}`")]
+    InvalidRtspMount { mount: String },
+
+    #[error("`rtsp.username` and `rtsp.password` must be set together")]
+    IncompleteRtspCredentials,
+
+    #[error(
+        "`display.roi` {width}x{height} at ({x}, {y}) does not fit the \
+         {capture_width}x{capture_height} capture"
+    )]
+    RoiOutOfBounds {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        capture_width: u32,
+        capture_height: u32,
+    },
+
+    #[error("`capture.format` {format:?} cannot be produced by the {backend:?} backend")]
+    FormatUnsupported {
+        format: PixelFormat,
+        backend: CaptureBackend,
+    },
+}
+
+fn list_issues(issues: &[InvalidField]) -> String {
+    issues
+        .iter()
+        .map(|issue| format!("\n  - {}", issue))
+        .collect()
+}
+
+impl Config {
+    /// Check the configuration, reporting every invalid field at once
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut issues = Vec::new();
+        let capture = &self.capture;
+        let pipeline = &self.pipeline;
+
+        let positive = [
+            ("capture.width", capture.width),
+            ("capture.height", capture.height),
+            ("capture.fps", capture.fps),
+            ("capture.buffer_count", capture.buffer_count),
+            ("capture.reconnect_backoff_ms", capture.reconnect_backoff_ms),
+            ("display.width", self.display.width),
+            ("display.height", self.display.height),
+            (
+                "gstreamer.buffer_pool_size",
+                self.gstreamer.buffer_pool_size,
+            ),
+            (
+                "gstreamer.overlay_font_size",
+                self.gstreamer.overlay_font_size,
+            ),
+            ("recording.bitrate_kbps", self.recording.bitrate_kbps),
+            ("rtsp.port", self.rtsp.port.into()),
+            ("rtsp.bitrate_kbps", self.rtsp.bitrate_kbps),
+            ("http.port", self.http.port.into()),
+            (
+                "recording.pre_event_seconds",
+                self.recording.pre_event_seconds,
+            ),
+        ];
+        for (field, value) in positive {
+            if value == 0 {
+                issues.push(InvalidField::Zero { field });
+            }
+        }
+        if pipeline.decode_threads == 0 {
+            issues.push(InvalidField::Zero {
+                field: "pipeline.decode_threads",
+            });
+        }
+
+        // Packed 4:2:2 shares chroma between horizontal pairs; 4:2:0 also vertically
+        let even_fields: &[(&'static str, u32)] = match capture.format {
+            PixelFormat::Yuyv4 => &[("capture.width", capture.width)],
+            PixelFormat::Nv12 => &[
+                ("capture.width", capture.width),
+                ("capture.height", capture.height),
+            ],
+            _ => &[],
+        };
+        for &(field, value) in even_fields {
+            if value % 2 != 0 {
+                issues.push(InvalidField::OddDimension {
+                    field,
+                    value,
+                    format: capture.format,
+                });
+            }
+        }
+
+        if !capture.backend.is_available() {
+            issues.push(InvalidField::BackendUnavailable {
+                backend: capture.backend,
+            });
+        }
+        if capture.backend.uses_path() && capture.device.path.is_empty() {
+            issues.push(InvalidField::MissingSourcePath {
+                backend: capture.backend,
+            });
+        }
+        // Synthetic sources encode each frame on its own
+        let synthetic = matches!(
+            capture.backend,
+            CaptureBackend::File | CaptureBackend::ImageSequence | CaptureBackend::TestPattern
+        );
+        if synthetic && capture.format.is_inter_coded() {
+            issues.push(InvalidField::FormatUnsupported {
+                format: capture.format,
+                backend: capture.backend,
+            });
+        }
+        if capture.backend != CaptureBackend::GStreamer && is_network_uri(&capture.device.path) {
+            issues.push(InvalidField::NetworkNeedsGStreamer {
+                backend: capture.backend,
+            });
+        }
+
+        if self.display.sink == VideoSink::File && self.display.sink_path.is_empty() {
+            issues.push(InvalidField::MissingSinkPath);
+        }
+        if self.recording.output_dir.is_empty() {
+            issues.push(InvalidField::MissingRecordingDir);
+        }
+        let passthrough = [
+            ("recording.mode", self.recording.mode),
+            ("rtsp.mode", self.rtsp.mode),
+        ];
+        for (field, mode) in passthrough {
+            if mode != RecordingMode::Passthrough || capture.delivers_jpeg() {
+                continue;
+            }
+            if is_network_uri(&capture.device.path) {
+                issues.push(InvalidField::PassthroughNeedsJpegStream {
+                    field,
+                    device: capture.device.path.clone(),
+                });
+            } else {
+                issues.push(InvalidField::PassthroughNeedsMjpeg {
+                    field,
+                    format: capture.format,
+                });
+            }
+        }
+        if !(1..=100).contains(&self.http.jpeg_quality) {
+            issues.push(InvalidField::OutOfRange {
+                field: "http.jpeg_quality",
+                value: self.http.jpeg_quality,
+                min: 1,
+                max: 100,
+            });
+        }
+        if self.rtsp.enabled && !cfg!(feature = "rtsp") {
+            issues.push(InvalidField::RtspUnavailable);
+        }
+        if !self.rtsp.mount.starts_with('/') {
+            issues.push(InvalidField::InvalidRtspMount {
+                mount: self.rtsp.mount.clone(),
+            });
+        }
+        if self.rtsp.username.is_some() != self.rtsp.password.is_some() {
+            issues.push(InvalidField::IncompleteRtspCredentials);
+        }
+        // Streams and files bring their own size; the pipeline checks the ROI
+        // against it once caps are negotiated
+        let sized_by_config = !capture.backend.uses_path() && !is_network_uri(&capture.device.path);
+        if let Some(roi) = self.display.roi {
+            if roi.width == 0 || roi.height == 0 {
+                issues.push(InvalidField::Zero {
+                    field: if roi.width == 0 {
+                        "display.roi.width"
+                    } else {
+                        "display.roi.height"
+                    },
+                });
+            } else if sized_by_config && !roi.fits(capture.width, capture.height) {
+                issues.push(InvalidField::RoiOutOfBounds {
+                    x: roi.x,
+                    y: roi.y,
+                    width: roi.width,
+                    height: roi.height,
+                    capture_width: capture.width,
+                    capture_height: capture.height,
+                });
+            }
+        }
+
+        if let (Some(frame_period_ms), Some(min_latency_ms)) =
+            (capture.frame_period_ms(), capture.min_latency_ms())
+        {
+            if pipeline.target_latency_ms < min_latency_ms {
+                issues.push(InvalidField::LatencyBelowFramePeriod {
+                    target_latency_ms: pipeline.target_latency_ms,
+                    fps: capture.fps,
+                    frame_period_ms,
+                });
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { issues })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Roi;
+
+    fn issues(config: &Config) -> Vec<InvalidField> {
+        config.validate().err().map_or_else(Vec::new, |e| e.issues)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_issue_at_once() {
+        let mut config = Config::default();
+        config.capture.width = 0;
+        config.display.height = 0;
+        config.pipeline.decode_threads = 0;
+        assert_eq!(
+            issues(&config),
+            vec![
+                InvalidField::Zero {
+                    field: "capture.width"
+                },
+                InvalidField::Zero {
+                    field: "display.height"
+                },
+                InvalidField::Zero {
+                    field: "pipeline.decode_threads"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn subsampled_formats_need_even_sizes() {
+        let mut config = Config::default();
+        config.capture.format = PixelFormat::Nv12;
+        config.capture.width = 1279;
+        config.capture.height = 721;
+        assert_eq!(
+            issues(&config),
+            vec![
+                InvalidField::OddDimension {
+                    field: "capture.width",
+                    value: 1279,
+                    format: PixelFormat::Nv12,
+                },
+                InvalidField::OddDimension {
+                    field: "capture.height",
+                    value: 721,
+                    format: PixelFormat::Nv12,
+                },
+            ]
+        );
+
+        config.capture.format = PixelFormat::Yuyv4;
+        config.capture.width = 1280;
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn latency_covers_one_frame() {
+        let mut config = Config::default();
+        config.capture.fps = 10;
+        assert_eq!(
+            issues(&config),
+            vec![InvalidField::LatencyBelowFramePeriod {
+                target_latency_ms: 32,
+                fps: 10,
+                frame_period_ms: 100,
+            }]
+        );
+    }
+
+    #[test]
+    fn roi_must_fit_the_capture() {
+        let mut config = Config::default();
+        config.display.roi = Some(Roi {
+            x: 1000,
+            y: 0,
+            width: 1000,
+            height: 1080,
+        });
+        assert_eq!(
+            issues(&config),
+            vec![InvalidField::RoiOutOfBounds {
+                x: 1000,
+                y: 0,
+                width: 1000,
+                height: 1080,
+                capture_width: 1920,
+                capture_height: 1080,
+            }]
+        );
+
+        // A stream's size is only known once the pipeline negotiates it
+        config.capture.device.path = "rtsp://camera.local/stream1".into();
+        config.capture.format = PixelFormat::H264;
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn passthrough_needs_a_jpeg_source() {
+        let mut config = Config::default();
+        config.recording.mode = RecordingMode::Passthrough;
+        assert_eq!(config.validate(), Ok(()));
+
+        config.capture.format = PixelFormat::H264;
+        assert_eq!(
+            issues(&config),
+            vec![InvalidField::PassthroughNeedsMjpeg {
+                field: "recording.mode",
+                format: PixelFormat::H264,
+            }]
+        );
+
+        // `format` says MJPEG, but RTSP cameras send H.264 regardless
+        config.capture.format = PixelFormat::Mjpeg;
+        config.capture.device.path = "rtsp://camera.local/stream1".into();
+        assert_eq!(
+            issues(&config),
+            vec![InvalidField::PassthroughNeedsJpegStream {
+                field: "recording.mode",
+                device: "rtsp://camera.local/stream1".into(),
+            }]
+        );
+
+        config.capture.device.path = "http://camera.local/video.mjpg".into();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rtsp_credentials_come_in_pairs() {
+        let mut config = Config::default();
+        config.rtsp.username = Some("viewer".into());
+        assert_eq!(
+            issues(&config),
+            vec![InvalidField::IncompleteRtspCredentials]
+        );
+    }
+
+    #[test]
+    fn stream_uris_need_the_gstreamer_backend() {
+        let mut config = Config::default();
+        config.capture.backend = CaptureBackend::V4l2;
+        config.capture.device.path = "udp://0.0.0.0:5000".into();
+        assert_eq!(
+            issues(&config),
+            vec![InvalidField::NetworkNeedsGStreamer {
+                backend: CaptureBackend::V4l2,
+            }]
+        );
+    }
+}